@@ -0,0 +1,69 @@
+// Interval search on the bundled Movescount exports of one run and on a
+// synthetic 10-hour activity:
+//
+//     cargo bench --bench intervals
+//
+// To compare a change against the code before it:
+//
+//     git stash && cargo bench --bench intervals -- --save-baseline before
+//     git stash pop && cargo bench --bench intervals -- --baseline before
+
+use {
+    criterion::{Criterion, criterion_group, criterion_main},
+    nom_fun::{
+        gpx::{Gpx, IntervalDetectionConfig},
+        misc,
+    },
+    std::{fmt::Write, hint::black_box, path::Path, str::FromStr, time::Duration},
+};
+
+const LONG_SECONDS: i64 = 10 * 60 * 60;
+
+// One sample a second at 2.5 m/s, except for the first 75 s of every 10
+// minutes, which are at 4 m/s.
+fn long_activity() -> Gpx {
+    let start = chrono::DateTime::from_timestamp(1_545_055_169, 0).unwrap();
+    let mut trkpts = String::new();
+    for second in 0..LONG_SECONDS {
+        let time = start + chrono::TimeDelta::seconds(second);
+        let speed = if second % 600 < 75 { 4.0 } else { 2.5 };
+        // Debug is RFC 3339
+        write!(
+            trkpts,
+            r#"<trkpt lat="35.0" lon="-106.5"><time>{time:?}</time><speed>{speed}</speed></trkpt>"#
+        )
+        .unwrap();
+    }
+    Gpx::from_str(&format!("<gpx><trk><trkseg>{trkpts}</trkseg></trk></gpx>")).unwrap()
+}
+
+fn intervals(c: &mut Criterion) {
+    nom_fun::set_tz(Some(chrono_tz::Tz::America__Denver));
+    let config = IntervalDetectionConfig::default();
+    let duration = Duration::from_secs(75);
+    let rest = Duration::from_secs(30);
+
+    for extension in ["fit", "kml", "xlsx"] {
+        let name = format!("assets/Move_2018_12_17_06_59_29_Running.{extension}");
+        let gpx = misc::activity_from(Path::new(&name)).unwrap();
+
+        c.bench_function(&format!("interval_report {extension}"), |b| {
+            b.iter(|| black_box(&gpx).interval_report(duration, rest, 13, &config))
+        });
+        c.bench_function(&format!("distance_interval_report {extension}"), |b| {
+            b.iter(|| black_box(&gpx).distance_interval_report(250.0, rest, 13, &config))
+        });
+    }
+
+    let gpx = long_activity();
+    let count = (LONG_SECONDS / 600) as usize;
+    c.bench_function("interval_report 10 hours", |b| {
+        b.iter(|| black_box(&gpx).interval_report(duration, rest, count, &config))
+    });
+    c.bench_function("distance_interval_report 10 hours", |b| {
+        b.iter(|| black_box(&gpx).distance_interval_report(300.0, rest, count, &config))
+    });
+}
+
+criterion_group!(benches, intervals);
+criterion_main!(benches);
@@ -0,0 +1,228 @@
+// Best efforts: the fastest stretch of an activity over each of a set of
+// distances (400 m through the half marathon) or durations (1 through 60
+// minutes), wherever in the activity it was run.
+//
+// These are found with the same sliding windows that interval detection
+// ranks, so a best effort's pace agrees with the pace of an interval over
+// the same stretch.  An activity that's shorter than an effort has no best
+// effort for it.
+
+use {
+    crate::gpx::{Gpx, Interval, METERS_PER_MILE, seconds_between},
+    chrono::{DateTime, Utc},
+    digital_duration_nom::duration::Duration,
+    std::{
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+        time,
+    },
+};
+
+const METERS_PER_HALF_MARATHON: f64 = 21_097.5;
+const METERS_PER_MARATHON: f64 = 42_195.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effort {
+    Meters(f64),
+    Seconds(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct BestEffort {
+    pub effort: Effort,
+    pub interval: Interval,
+}
+
+#[derive(Debug)]
+pub struct BestEfforts {
+    origin: Option<DateTime<Utc>>,
+    pub efforts: Vec<BestEffort>,
+}
+
+impl Effort {
+    pub const STANDARD: [Self; 10] = [
+        Effort::Meters(400.0),
+        Effort::Meters(1000.0),
+        Effort::Meters(METERS_PER_MILE),
+        Effort::Meters(5000.0),
+        Effort::Meters(10_000.0),
+        Effort::Meters(METERS_PER_HALF_MARATHON),
+        Effort::Seconds(60),
+        Effort::Seconds(5 * 60),
+        Effort::Seconds(20 * 60),
+        Effort::Seconds(60 * 60),
+    ];
+}
+
+// Accepts what Display writes, plus a few common spellings (e.g., "5k",
+// "mi" and "half-marathon").
+impl FromStr for Effort {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let number = |digits: &str| digits.parse::<f64>().ok().filter(|n| *n > 0.0);
+        let effort = match string {
+            "mile" | "mi" | "1mi" => Some(Effort::Meters(METERS_PER_MILE)),
+            "half" | "half-marathon" => Some(Effort::Meters(METERS_PER_HALF_MARATHON)),
+            "marathon" => Some(Effort::Meters(METERS_PER_MARATHON)),
+            _ => {
+                if let Some(minutes) = string.strip_suffix("min") {
+                    minutes.parse::<u64>().ok().map(|m| Effort::Seconds(m * 60))
+                } else if let Some(seconds) = string.strip_suffix('s') {
+                    seconds.parse::<u64>().ok().map(Effort::Seconds)
+                } else if let Some(km) = string
+                    .strip_suffix("km")
+                    .or_else(|| string.strip_suffix('k'))
+                {
+                    number(km).map(|km| Effort::Meters(km * 1000.0))
+                } else {
+                    string
+                        .strip_suffix('m')
+                        .and_then(number)
+                        .map(Effort::Meters)
+                }
+            }
+        };
+        effort.filter(|e| *e != Effort::Seconds(0)).ok_or_else(|| {
+            format!(
+                "unknown effort \"{string}\" (expected e.g. 400m, 5km, mile, half, marathon or 20min)"
+            )
+        })
+    }
+}
+
+impl Display for Effort {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Effort::Meters(METERS_PER_MILE) => f.pad("mile"),
+            Effort::Meters(METERS_PER_HALF_MARATHON) => f.pad("half"),
+            Effort::Meters(METERS_PER_MARATHON) => f.pad("marathon"),
+            Effort::Meters(meters) if meters % 1000.0 == 0.0 => {
+                f.pad(&format!("{}km", meters / 1000.0))
+            }
+            Effort::Meters(meters) => f.pad(&format!("{meters}m")),
+            Effort::Seconds(seconds) if seconds % 60 == 0 => f.pad(&format!("{}min", seconds / 60)),
+            Effort::Seconds(seconds) => f.pad(&format!("{seconds}s")),
+        }
+    }
+}
+
+impl BestEffort {
+    pub fn start(&self) -> DateTime<Utc> {
+        self.interval.start()
+    }
+
+    pub fn stop(&self) -> DateTime<Utc> {
+        self.interval.stop()
+    }
+
+    // Per mile
+    pub fn pace(&self) -> Duration {
+        self.interval.pace()
+    }
+
+    pub fn seconds(&self) -> f64 {
+        seconds_between(self.start(), self.stop())
+    }
+
+    pub fn meters(&self) -> f64 {
+        match self.effort {
+            Effort::Meters(meters) => meters,
+            Effort::Seconds(_) => self.interval.meters_per_second() * self.seconds(),
+        }
+    }
+}
+
+pub fn best_effort(gpx: &Gpx, effort: Effort) -> Option<BestEffort> {
+    let interval = match effort {
+        Effort::Meters(meters) => gpx.fastest_over(meters),
+        Effort::Seconds(seconds) => gpx.fastest_for(time::Duration::from_secs(seconds)),
+    }?;
+    Some(BestEffort { effort, interval })
+}
+
+pub fn best_efforts(gpx: &Gpx, efforts: &[Effort]) -> BestEfforts {
+    BestEfforts {
+        origin: gpx.trkpts().first().map(|t| t.time),
+        efforts: efforts
+            .iter()
+            .filter_map(|&effort| best_effort(gpx, effort))
+            .collect(),
+    }
+}
+
+impl Display for BestEfforts {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:>9} {:>9} {:>7} {:>7}",
+            "Best", "Start", "Time", "Pace", "Meters"
+        )?;
+        for best in &self.efforts {
+            let start = seconds_between(self.origin.unwrap_or(best.start()), best.start());
+            writeln!(
+                f,
+                "{:>8} {:9.1} {:9.1} {:7.1} {:7.0}",
+                best.effort,
+                Duration::from(start),
+                Duration::from(best.seconds()),
+                best.pace(),
+                best.meters()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::gpx::Trkpt};
+
+    #[test]
+    fn test_effort() {
+        for effort in Effort::STANDARD {
+            assert_eq!(Ok(effort), effort.to_string().parse());
+        }
+        assert_eq!(Ok(Effort::Meters(5000.0)), "5k".parse());
+        assert_eq!(Ok(Effort::Meters(METERS_PER_MILE)), "mi".parse());
+        assert_eq!(Ok(Effort::Seconds(90)), "90s".parse());
+        assert!("0min".parse::<Effort>().is_err());
+        assert!("fast".parse::<Effort>().is_err());
+    }
+
+    #[test]
+    fn test_best_efforts() {
+        // 2 m/s, except for 300 s at 4 m/s after the first 1000 s
+        let trkpts = (0..=2300)
+            .map(|second| {
+                Trkpt::at(second).with_speed(if (1001..=1300).contains(&second) {
+                    4.0
+                } else {
+                    2.0
+                })
+            })
+            .collect::<Vec<_>>();
+        let gpx = Gpx::from(trkpts);
+        let best = best_efforts(&gpx, &Effort::STANDARD).efforts;
+
+        // Too short for 10 km, the half marathon and 60 minutes
+        let efforts = best
+            .iter()
+            .map(|b| b.effort.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["400m", "1km", "mile", "5km", "1min", "5min", "20min"],
+            efforts
+        );
+        let km = &best[1];
+        assert!((km.seconds() - 250.0).abs() < 1e-6);
+        assert_eq!(
+            Duration::from(METERS_PER_MILE / 4.0).as_secs(),
+            km.pace().as_secs()
+        );
+        assert!(km.start() >= gpx.trkpts()[1000].time);
+        assert!((best[4].meters() - 240.0).abs() < 1e-6);
+        // 300 s at 4 m/s and 900 s at 2 m/s
+        assert!((best[6].meters() - 3000.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,41 @@
+// Compares exports of the same activity in different formats, e.g.,
+//
+// target/release/compare assets/Move_2018_12_17_06_59_29_Running.{fit,kml,xlsx}
+//
+// The first file is the reference that the others are measured against.
+
+use {
+    chrono_tz::Tz,
+    clap::Parser,
+    nom_fun::{compare, misc},
+    std::{io::Result, path::PathBuf},
+};
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+    nom_fun::set_tz(opt.time_zone);
+
+    let activities = opt
+        .files
+        .iter()
+        .map(|path| {
+            let name = path.extension().map_or_else(
+                || path.display().to_string(),
+                |e| e.to_string_lossy().into(),
+            );
+            Ok((name, misc::activity_from(path)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    print!("{}", compare::compare(&activities));
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[arg(short, long)]
+    pub time_zone: Option<Tz>,
+    /// Exports of one activity; the first is the reference
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+}
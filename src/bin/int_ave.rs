@@ -3,59 +3,202 @@ extern crate nom_fun;
 use {
     chrono_tz::Tz,
     clap::Parser,
-    digital_duration_nom::duration::Duration,
-    nom_fun::{gpx::Gpx, misc},
+    digital_duration_nom::duration::{Duration, duration_parser},
+    nom::{Parser as _, combinator::all_consuming},
+    nom_fun::{
+        best::{self, Effort},
+        critical_speed::{BalanceReport, CriticalSpeed, Performance},
+        detect::{self, DetectionConfig},
+        elevation::{self, ElevationConfig, ElevationFilter},
+        export::{self, Sheet},
+        gpx::{CadenceUnit, Gpx, IntervalDetectionConfig, IntervalReport, RankBy, Trim},
+        heart_rate::HeartRateReport,
+        hills::{self, HillConfig},
+        misc,
+        pause::{MovingReport, PauseConfig},
+        power::{Air, PowerConfig, PowerReport},
+        predict::{self, RACES},
+        records::Records,
+        speed::{SpeedConfig, SpeedSmoothing},
+        splits::{self, SplitUnit},
+        workout::Workout,
+        xlsx,
+        zones::{Metric, Profile, ZoneReport},
+    },
     std::{
-        io::{self, Result},
-        path::PathBuf,
+        fs::File,
+        io::{self, BufWriter, Result},
+        path::{Path, PathBuf},
         str::FromStr,
+        time,
     },
 };
 
 pub fn main() -> Result<()> {
     let opt = Opt::parse();
     nom_fun::set_tz(opt.time_zone);
+    opt.config().validate().map_err(io::Error::other)?;
+    let mut interval_sheets = Vec::new();
+    let mut sample_sheets = Vec::new();
+    let profile = opt.zones.as_deref().map(Profile::load).transpose()?;
+    let critical_speed = opt
+        .records
+        .as_deref()
+        .map(Records::load)
+        .transpose()?
+        .map(|records| CriticalSpeed::fit(&records.best_performances(None)));
 
-    for path in opt.files {
-        let contents = misc::contents_from(&path)?;
-        match path.extension().map(std::ffi::OsStr::to_str) {
+    for path in &opt.files {
+        let analyzed = match path.extension().map(std::ffi::OsStr::to_str) {
             None => {
+                let contents = misc::contents_from(path)?;
                 if let Some(average) = average_from_string(&contents) {
                     println!("Average: {:.1}", average);
                 }
+                if opt.predict {
+                    let performances = predict::performances_from_log(&contents);
+                    print!("{}", predict::predict(&performances, &RACES));
+                }
+                None
+            }
+            Some(None) => {
+                println!("Non-UTF8 extension");
+                None
             }
-            Some(None) => println!("Non-UTF8 extension"),
-            Some(Some("fit")) => println!("FIT"),
             Some(Some("gpx")) => {
+                let contents = misc::contents_from(path)?;
                 let mut gpx = Gpx::from_str(&contents).map_err(io::Error::other)?;
+                opt.prepare(&mut gpx);
+                let mut parameters = None;
                 if gpx.already_has_meters_per_second() {
+                    let p = opt.parameters(&gpx);
                     println!("Old:");
-                    gpx.analyze(
-                        opt.interval_duration,
-                        opt.interval_rest,
-                        opt.interval_count,
-                        false,
-                    );
+                    opt.report(&gpx, p, false)?;
                     println!("New:");
+                    parameters = Some(p);
+                }
+                let config = SpeedConfig {
+                    smoothing: opt.speed_smoothing,
+                    ..Default::default()
+                };
+                let corrections = gpx.fill_in_meters_per_second(&config);
+                if !corrections.is_empty() {
+                    println!("Corrected {} implausible speeds", corrections.len());
+                }
+                if opt.corrections {
+                    for correction in &corrections {
+                        println!("{correction}");
+                    }
                 }
-                gpx.fill_in_meters_per_second();
                 // println!("{:?}", gpx);
-                gpx.analyze(
-                    opt.interval_duration,
-                    opt.interval_rest,
-                    opt.interval_count,
-                    opt.tod,
+                opt.summarize(&gpx);
+                let parameters = parameters.unwrap_or_else(|| opt.parameters(&gpx));
+                let report = opt.report(&gpx, parameters, opt.tod)?;
+                Some((gpx, report))
+            }
+            Some(Some("tcx")) => {
+                println!("TCX");
+                None
+            }
+            Some(Some("fit" | "kml" | "xlsx")) => {
+                // These all come with speeds, so unlike GPX there's no need
+                // to fill them in.
+                let mut gpx = misc::activity_from(path)?;
+                opt.prepare(&mut gpx);
+                opt.summarize(&gpx);
+                let report = opt.report(&gpx, opt.parameters(&gpx), opt.tod)?;
+                Some((gpx, report))
+            }
+            Some(Some(extension)) => {
+                println!("Unknown extension {}", extension);
+                None
+            }
+        };
+
+        if let Some((gpx, report)) = analyzed {
+            if opt.moving {
+                print!(
+                    "{}",
+                    MovingReport::new(&gpx, &report, &PauseConfig::default())
                 );
             }
-            Some(Some("kml")) => println!("KML"),
-            Some(Some("tcx")) => println!("TCX"),
-            Some(Some("xlsx")) => println!("XLSX"),
-            Some(Some(extension)) => println!("Unknown extension {}", extension),
+            if opt.heart_rate {
+                match HeartRateReport::new(&gpx, &report) {
+                    Some(heart_rates) => print!("{heart_rates}"),
+                    None => println!("No heart rates"),
+                }
+            }
+            if let Some(mass_kg) = opt.power {
+                let config = PowerConfig {
+                    filter: opt.elevation_filter.unwrap_or(ElevationFilter::KALMAN),
+                    air: opt.headwind.map(|headwind_mps| Air {
+                        headwind_mps,
+                        ..Default::default()
+                    }),
+                    ..PowerConfig::new(mass_kg)
+                };
+                print!("{}", PowerReport::new(&gpx, &report, &config));
+            }
+            if opt.predict {
+                let performances = report
+                    .intervals
+                    .iter()
+                    .map(Performance::from)
+                    .chain(
+                        best::best_efforts(&gpx, &Effort::STANDARD)
+                            .efforts
+                            .iter()
+                            .map(Performance::from),
+                    )
+                    .collect::<Vec<_>>();
+                print!("{}", predict::predict(&performances, &RACES));
+            }
+            match &critical_speed {
+                Some(Some(cs)) => {
+                    print!("{}", BalanceReport::new(&gpx, &report, cs.clone()))
+                }
+                Some(None) => {
+                    println!("Not enough records of different lengths for critical speed")
+                }
+                None => {}
+            }
+            if let Some(profile) = &profile {
+                for metric in [Metric::HeartRate, Metric::Pace] {
+                    if let Some(zones) = ZoneReport::new(&gpx, &report, profile, metric) {
+                        print!("{zones}");
+                    }
+                }
+            }
+            let name = path
+                .file_stem()
+                .map_or_else(|| "activity".into(), |stem| stem.to_string_lossy());
+            if opt.export.is_some() {
+                interval_sheets.push(gpx.interval_sheet(&name, &report));
+            }
+            if opt.export_samples.is_some() {
+                sample_sheets.push(gpx.sample_sheet(&name));
+            }
         }
     }
+
+    if let Some(path) = opt.export {
+        write_sheets(&path, &interval_sheets)?;
+    }
+    if let Some(path) = opt.export_samples {
+        write_sheets(&path, &sample_sheets)?;
+    }
     Ok(())
 }
 
+// The extension picks the format: xlsx for a workbook, anything else is CSV.
+fn write_sheets(path: &Path, sheets: &[Sheet]) -> Result<()> {
+    let file = File::create(path)?;
+    match path.extension().and_then(std::ffi::OsStr::to_str) {
+        Some("xlsx") => xlsx::write_workbook(sheets, file).map_err(io::Error::other),
+        _ => export::write_csv(sheets, BufWriter::new(file)),
+    }
+}
+
 fn average_from_string(content: &str) -> Option<Duration> {
     let pairs = nom_fun::interval_parse::many_pace_duration_pairs(content)
         .unwrap()
@@ -70,21 +213,244 @@ fn average_from_string(content: &str) -> Option<Duration> {
     }
 }
 
+// Accepts anything digital_duration_nom can parse, e.g., 75, 1:15 or 1:15.5
+fn std_duration(string: &str) -> std::result::Result<time::Duration, String> {
+    all_consuming(duration_parser)
+        .parse(string)
+        .map(|(_, duration)| duration.into())
+        .map_err(|_| format!("bad duration \"{string}\""))
+}
+
+// Intervals can only cover a finite distance of more than 0 meters.
+fn positive_meters(string: &str) -> std::result::Result<f64, String> {
+    match string.parse::<f64>() {
+        Ok(meters) if meters.is_finite() && meters > 0.0 => Ok(meters),
+        _ => Err(format!("bad distance \"{string}\" (expected meters > 0)")),
+    }
+}
+
+// Interval duration, rest and count
+type Parameters = (time::Duration, time::Duration, usize);
+
 #[derive(Parser, Debug)]
 struct Opt {
-    /// Duration (seconds) of each interval
-    #[arg(short = 'd', long = "interval-duration", default_value = "75")]
-    pub interval_duration: u8,
-    /// Seconds of rest between intervals
-    #[arg(short = 'r', long = "interval-rest", default_value = "30")]
-    pub interval_rest: u8,
-    /// Number of intervals to find within file
-    #[arg(short = 'c', long = "interval-count", default_value = "12")]
-    pub interval_count: u8,
+    /// Duration (e.g., 75 or 4:00) of each interval [default: 1:15]
+    #[arg(short = 'd', long = "interval-duration", value_parser = std_duration)]
+    pub interval_duration: Option<time::Duration>,
+    /// Length (meters) of each interval, e.g., 400 for 8×400 m on a track
+    #[arg(
+        short = 'm',
+        long,
+        conflicts_with = "interval_duration",
+        value_parser = positive_meters,
+        allow_negative_numbers = true
+    )]
+    pub interval_meters: Option<f64>,
+    /// Rest (e.g., 30 or 1:30) between intervals [default: 0:30]
+    #[arg(short = 'r', long = "interval-rest", value_parser = std_duration)]
+    pub interval_rest: Option<time::Duration>,
+    /// Number of intervals to find within file [default: 12]
+    #[arg(short = 'c', long = "interval-count")]
+    pub interval_count: Option<usize>,
+    /// Detect whichever of duration, rest and count weren't given
+    #[arg(short = 'a', long, default_value_t = false)]
+    pub auto: bool,
+    /// Find a structured workout, e.g., "4×(3:00/1:30), 6×(1:00/1:00), 2:00
+    /// rest between sets", instead of equal intervals
+    #[arg(short = 'w', long, conflicts_with_all = ["interval_duration", "interval_meters", "interval_rest", "interval_count", "auto"])]
+    pub workout: Option<Workout>,
     #[arg(short, long)]
     pub time_zone: Option<Tz>,
     #[arg()]
     pub files: Vec<PathBuf>,
     #[arg(long, default_value_t = false)]
     pub tod: bool, // show time-of-day instead of elapsed for startt and stop
+    /// How far apart (as a multiple of interval plus rest) intervals in one
+    /// set may start
+    #[arg(long, default_value_t = 1.5)]
+    pub span_slop: f64,
+    /// How fast (relative to the fastest) intervals in the set must be
+    #[arg(long, default_value_t = 0.70)]
+    pub min_rank_ratio: f64,
+    /// Which intervals to drop when too many are found: slower-end, start
+    /// or end
+    #[arg(long, default_value = "slower-end")]
+    pub trim: Trim,
+    /// Rank intervals by speed or by grade-adjusted speed (gap)
+    #[arg(long, default_value = "speed")]
+    pub rank_by: RankBy,
+    /// How cadence was recorded: strides (per leg, as Garmin and Suunto
+    /// do), steps or auto
+    #[arg(long, default_value = "auto")]
+    pub cadence: CadenceUnit,
+    /// Smooth elevations (and vertical speeds) with none, median or kalman
+    #[arg(long)]
+    pub elevation_filter: Option<ElevationFilter>,
+    /// Smooth speeds computed from GPX positions with none, moving-average,
+    /// savitzky-golay or kalman
+    #[arg(long, default_value = "none")]
+    pub speed_smoothing: SpeedSmoothing,
+    /// Show each implausible speed computed from GPX positions and what
+    /// replaced it
+    #[arg(long, default_value_t = false)]
+    pub corrections: bool,
+    /// Show mile (mi) or kilometer (km) splits
+    #[arg(long)]
+    pub splits: Option<SplitUnit>,
+    /// Show the fastest 400m, 1km, mile, 5km, 10km and half marathon and
+    /// the fastest 1, 5, 20 and 60 minutes
+    #[arg(long, default_value_t = false)]
+    pub best_efforts: bool,
+    /// Show the total ascent and descent
+    #[arg(long, default_value_t = false)]
+    pub climbing: bool,
+    /// Find hill repeats (climbs) instead of intervals
+    #[arg(long, default_value_t = false, conflicts_with_all = ["workout", "interval_meters"])]
+    pub hills: bool,
+    /// Show pauses and the moving time and pace of the activity and of each
+    /// interval
+    #[arg(long, default_value_t = false)]
+    pub moving: bool,
+    /// Show heart rate, time to peak and recovery for each interval
+    #[arg(long, default_value_t = false)]
+    pub heart_rate: bool,
+    /// Estimate running power for a runner of this many kilograms and show
+    /// the average and normalized power of the activity and each interval
+    #[arg(long)]
+    pub power: Option<f64>,
+    /// Include air resistance in the power, with this headwind in m/s
+    /// (negative for a tailwind)
+    #[arg(long, requires = "power", allow_negative_numbers = true)]
+    pub headwind: Option<f64>,
+    /// Predict race times from the intervals and the activity's best
+    /// efforts (or from the paces in an interval log)
+    #[arg(long, default_value_t = false)]
+    pub predict: bool,
+    /// Show time in the heart rate and pace zones of the athlete profile in
+    /// this file, for the activity and each interval
+    #[arg(long)]
+    pub zones: Option<PathBuf>,
+    /// Fit critical speed and D′ to the records in this database (see the
+    /// records binary) and show the D′ balance through the intervals and
+    /// the pace that would have used it all
+    #[arg(long)]
+    pub records: Option<PathBuf>,
+    /// Show why each candidate interval was accepted or rejected
+    #[arg(long, default_value_t = false)]
+    pub explain: bool,
+    /// Write the intervals to this .csv or .xlsx file
+    #[arg(long)]
+    pub export: Option<PathBuf>,
+    /// Write every sample to this .csv or .xlsx file
+    #[arg(long)]
+    pub export_samples: Option<PathBuf>,
+}
+
+impl Opt {
+    fn config(&self) -> IntervalDetectionConfig {
+        IntervalDetectionConfig {
+            span_slop: self.span_slop,
+            min_rank_ratio: self.min_rank_ratio,
+            trim: self.trim,
+            rank_by: self.rank_by,
+            ..Default::default()
+        }
+    }
+
+    // Everything that's done to an activity before looking for intervals
+    fn prepare(&self, gpx: &mut Gpx) {
+        gpx.cadence_in_steps(self.cadence);
+        if self.climbing {
+            let config = ElevationConfig {
+                filter: self.elevation_filter.unwrap_or(ElevationFilter::KALMAN),
+                ..Default::default()
+            };
+            match elevation::total_climbing(gpx, &config) {
+                Some(climbing) => println!("{climbing}"),
+                None => println!("No elevations"),
+            }
+        }
+        if let Some(filter) = &self.elevation_filter {
+            gpx.smooth_elevation(filter);
+        }
+    }
+
+    // Whatever's shown about the activity as a whole, once it has speeds
+    fn summarize(&self, gpx: &Gpx) {
+        if let Some(unit) = self.splits {
+            print!("{}", splits::splits(gpx, unit));
+        }
+        if self.best_efforts {
+            print!("{}", best::best_efforts(gpx, &Effort::STANDARD));
+        }
+    }
+
+    fn report(&self, gpx: &Gpx, parameters: Parameters, tod: bool) -> Result<IntervalReport> {
+        if self.hills {
+            let hills = hills::hill_repeats(gpx, &HillConfig::default());
+            print!("{hills}");
+            return Ok(hills.interval_report(gpx));
+        }
+        match &self.workout {
+            Some(workout) => {
+                let report = workout.find(gpx).ok_or_else(|| {
+                    io::Error::other(format!("Couldn't find {workout} in the activity"))
+                })?;
+                gpx.dump(&report, tod);
+                Ok(report)
+            }
+            None => {
+                let (duration, rest, count) = parameters;
+                let config = self.config();
+                let explanation = match self.interval_meters {
+                    Some(meters) => gpx.explain_distance(meters, rest, count, &config),
+                    None => gpx.explain(duration, rest, count, &config),
+                }
+                .map_err(io::Error::other)?;
+                if self.explain {
+                    print!("{explanation}");
+                }
+                gpx.analyze(&explanation, tod).map_err(io::Error::other)
+            }
+        }
+    }
+
+    // Explicit options win, then (with --auto) whatever was detected, then
+    // the defaults.
+    fn parameters(&self, gpx: &Gpx) -> Parameters {
+        let explicit = (
+            self.interval_duration,
+            self.interval_rest,
+            self.interval_count,
+        );
+        let detected = match explicit {
+            (Some(_), Some(_), Some(_)) => None,
+            _ if !self.auto => None,
+            _ => {
+                let detected = detect::detect(gpx, &DetectionConfig::default());
+                match detected {
+                    Some(d) => println!(
+                        "Detected {} intervals of {} with {} rest",
+                        d.count,
+                        Duration::from(d.duration.as_secs_f64()),
+                        Duration::from(d.rest.as_secs_f64())
+                    ),
+                    None => println!("Couldn't detect intervals; using defaults"),
+                }
+                detected
+            }
+        };
+
+        (
+            explicit
+                .0
+                .or(detected.map(|d| d.duration))
+                .unwrap_or(time::Duration::from_secs(75)),
+            explicit
+                .1
+                .or(detected.map(|d| d.rest))
+                .unwrap_or(time::Duration::from_secs(30)),
+            explicit.2.or(detected.map(|d| d.count)).unwrap_or(12),
+        )
+    }
 }
@@ -0,0 +1,76 @@
+// Keeps a database of the best efforts in a directory of activities and
+// reports the personal records that new activities set, e.g.,
+//
+// target/release/records ~/runs --best mile --year 2018
+// target/release/records ~/runs --best 5km --top 10
+// target/release/records ~/runs --critical-speed --year 2018
+//
+// Only activities that are new or have changed since the last run are read.
+
+use {
+    chrono_tz::Tz,
+    clap::Parser,
+    nom_fun::{best::Effort, critical_speed::CriticalSpeed, records::Records},
+    std::{io::Result, path::PathBuf},
+};
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+    nom_fun::set_tz(opt.time_zone);
+
+    let database = opt
+        .database
+        .clone()
+        .unwrap_or_else(|| opt.directory.join("records.txt"));
+    let mut records = Records::load(&database)?;
+    let update = records.update(&opt.directory)?;
+    for (path, e) in &update.unreadable {
+        eprintln!("Couldn't read {}: {e}", path.display());
+    }
+    if update.added > 0 || update.removed > 0 {
+        println!(
+            "Added {} activities, removed {}",
+            update.added, update.removed
+        );
+        records.save(&database)?;
+    }
+    for new_record in &update.new_records {
+        println!("{new_record}");
+    }
+
+    if let Some(effort) = opt.best {
+        let mut leaderboard = records.leaderboard(effort, opt.year);
+        leaderboard.entries.truncate(opt.top);
+        print!("{leaderboard}");
+    }
+    if opt.critical_speed {
+        match CriticalSpeed::fit(&records.best_performances(opt.year)) {
+            Some(cs) => println!("{cs}"),
+            None => println!("Not enough records of different lengths for critical speed"),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[arg(short, long)]
+    pub time_zone: Option<Tz>,
+    /// Where the database is kept (default: records.txt in the directory)
+    #[arg(long)]
+    pub database: Option<PathBuf>,
+    /// Show the best of an effort, e.g., 400m, 5km, mile, half or 20min
+    #[arg(long)]
+    pub best: Option<Effort>,
+    /// Only use efforts from this year
+    #[arg(long)]
+    pub year: Option<i32>,
+    /// How many efforts to show
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+    /// Fit critical speed and D′ to the records
+    #[arg(long, default_value_t = false)]
+    pub critical_speed: bool,
+    /// The directory of activities (searched recursively)
+    pub directory: PathBuf,
+}
@@ -0,0 +1,67 @@
+// Reports the training load (TRIMP and hrTSS) of each activity in a
+// directory and the day by day fatigue (ATL), fitness (CTL) and form (TSB)
+// they add up to, e.g.,
+//
+// target/release/training_load --resting-hr 48 --max-hr 188 ~/runs
+// target/release/training_load --profile athlete.txt ~/runs
+
+use {
+    chrono_tz::Tz,
+    clap::Parser,
+    nom_fun::{
+        misc,
+        training_load::{self, Sex, TrainingLoad},
+        zones::Profile,
+    },
+    std::{
+        io::{self, Result},
+        path::PathBuf,
+    },
+};
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+    nom_fun::set_tz(opt.time_zone);
+
+    // The options override the profile.
+    let mut profile = match &opt.profile {
+        Some(path) => Profile::load(path)?,
+        None => Profile::default(),
+    };
+    profile.resting_hr = opt.resting_hr.or(profile.resting_hr);
+    profile.max_hr = opt.max_hr.or(profile.max_hr);
+    profile.threshold_hr = opt.threshold_hr.or(profile.threshold_hr);
+    profile.sex = opt.sex.or(profile.sex);
+    let limits = profile.heart_rate_limits().map_err(io::Error::other)?;
+    let mut loads = Vec::new();
+    for path in misc::activity_paths(&opt.directory)? {
+        match misc::activity_with_speeds_from(&path) {
+            Ok(gpx) => loads.extend(training_load::load(&gpx, &limits).map(|load| (path, load))),
+            Err(e) => eprintln!("Couldn't read {}: {e}", path.display()),
+        }
+    }
+    print!("{}", TrainingLoad::new(loads));
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[arg(short, long)]
+    pub time_zone: Option<Tz>,
+    /// An athlete profile (see zones.rs) with the heart rates
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+    #[arg(long)]
+    pub resting_hr: Option<f64>,
+    #[arg(long)]
+    pub max_hr: Option<f64>,
+    /// Lactate threshold heart rate (default: 85% of the heart rate reserve)
+    #[arg(long)]
+    pub threshold_hr: Option<f64>,
+    /// Which of Banister's TRIMP weightings to use: male (the default) or
+    /// female
+    #[arg(long)]
+    pub sex: Option<Sex>,
+    /// The directory of activities (searched recursively)
+    pub directory: PathBuf,
+}
@@ -0,0 +1,297 @@
+// Compares several exports of the same activity (e.g., the FIT, KML and
+// xlsx that Movescount produced for one run).  The first activity is the
+// reference; every other activity is sampled at the reference's timestamps,
+// interpolating between neighboring samples, and the differences are
+// summarized.
+
+use {
+    crate::gpx::{Gpx, Trkpt, seconds_between},
+    chrono::{DateTime, Utc},
+    geo::{Distance, Haversine, Point},
+    std::fmt::{self, Display, Formatter},
+};
+
+// Don't interpolate across gaps longer than this many seconds.
+const MAX_GAP_SECONDS: f64 = 5.0;
+
+#[derive(Debug)]
+pub struct Summary {
+    pub name: String,
+    pub points: usize,
+    pub start: Option<DateTime<Utc>>,
+    pub stop: Option<DateTime<Utc>>,
+    pub with_position: usize,
+    pub with_elevation: usize,
+    pub with_heart_rate: usize,
+    pub reported_meters: Option<f64>,
+    pub computed_meters: Option<f64>,
+}
+
+// Differences are always other minus reference.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub mean: f64,
+    pub mean_abs: f64,
+    pub max_abs: f64,
+}
+
+#[derive(Debug)]
+pub struct Difference {
+    pub name: String,
+    pub matched: usize,
+    pub position_meters: Option<Stats>,
+    pub elevation_meters: Option<Stats>,
+    pub heart_rate: Option<Stats>,
+    pub distance_meters: Option<Stats>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub summaries: Vec<Summary>,
+    pub differences: Vec<Difference>,
+}
+
+impl Stats {
+    fn from_differences(differences: &[f64]) -> Option<Self> {
+        if differences.is_empty() {
+            return None;
+        }
+        let count = differences.len();
+        let n = count as f64;
+
+        Some(Stats {
+            count,
+            mean: differences.iter().sum::<f64>() / n,
+            mean_abs: differences.iter().map(|d| d.abs()).sum::<f64>() / n,
+            max_abs: differences.iter().map(|d| d.abs()).fold(0.0, f64::max),
+        })
+    }
+}
+
+// The value of field at time, linearly interpolated between the samples
+// that surround it.
+fn value_at(
+    trkpts: &[Trkpt],
+    time: DateTime<Utc>,
+    field: fn(&Trkpt) -> Option<f64>,
+) -> Option<f64> {
+    let after = trkpts.partition_point(|t| t.time < time);
+    let next = trkpts.get(after)?;
+    if next.time == time {
+        return field(next);
+    }
+    let previous = &trkpts[after.checked_sub(1)?];
+    let span = seconds_between(previous.time, next.time);
+    if span > MAX_GAP_SECONDS {
+        return None;
+    }
+    let (v0, v1) = (field(previous)?, field(next)?);
+    let fraction = seconds_between(previous.time, time) / span;
+
+    Some(v0 + (v1 - v0) * fraction)
+}
+
+fn position(trkpt: &Trkpt) -> Option<Point> {
+    Some(Point::new(trkpt.lon?, trkpt.lat?))
+}
+
+fn computed_meters(trkpts: &[Trkpt]) -> Option<f64> {
+    let positions = trkpts.iter().filter_map(position).collect::<Vec<_>>();
+    if positions.len() < 2 {
+        return None;
+    }
+    Some(
+        positions
+            .windows(2)
+            .map(|pair| Haversine.distance(pair[0], pair[1]))
+            .sum(),
+    )
+}
+
+fn summarize(name: &str, gpx: &Gpx) -> Summary {
+    let trkpts = gpx.trkpts();
+
+    Summary {
+        name: name.to_string(),
+        points: trkpts.len(),
+        start: trkpts.first().map(|t| t.time),
+        stop: trkpts.last().map(|t| t.time),
+        with_position: trkpts.iter().filter_map(position).count(),
+        with_elevation: trkpts
+            .iter()
+            .filter(|t| t.elevation_meters.is_some())
+            .count(),
+        with_heart_rate: trkpts.iter().filter(|t| t.heart_rate.is_some()).count(),
+        reported_meters: trkpts.iter().rev().find_map(|t| t.meters),
+        computed_meters: computed_meters(trkpts),
+    }
+}
+
+fn differ(name: &str, reference: &[Trkpt], other: &[Trkpt]) -> Difference {
+    let mut matched = 0;
+    let mut positions = Vec::new();
+    let mut elevations = Vec::new();
+    let mut heart_rates = Vec::new();
+    let mut distances = Vec::new();
+    let difference = |field: fn(&Trkpt) -> Option<f64>, trkpt: &Trkpt| {
+        Some(value_at(other, trkpt.time, field)? - field(trkpt)?)
+    };
+
+    for trkpt in reference {
+        let Some(first) = other.first() else {
+            break;
+        };
+        if trkpt.time < first.time || trkpt.time > other[other.len() - 1].time {
+            continue;
+        }
+        matched += 1;
+
+        if let (Some(here), Some(lat), Some(lon)) = (
+            position(trkpt),
+            value_at(other, trkpt.time, |t| t.lat),
+            value_at(other, trkpt.time, |t| t.lon),
+        ) {
+            positions.push(Haversine.distance(here, Point::new(lon, lat)));
+        }
+        elevations.extend(difference(|t| t.elevation_meters, trkpt));
+        heart_rates.extend(difference(|t| t.heart_rate.map(f64::from), trkpt));
+        distances.extend(difference(|t| t.meters, trkpt));
+    }
+
+    Difference {
+        name: name.to_string(),
+        matched,
+        position_meters: Stats::from_differences(&positions),
+        elevation_meters: Stats::from_differences(&elevations),
+        heart_rate: Stats::from_differences(&heart_rates),
+        distance_meters: Stats::from_differences(&distances),
+    }
+}
+
+pub fn compare(activities: &[(String, Gpx)]) -> Report {
+    let summaries = activities
+        .iter()
+        .map(|(name, gpx)| summarize(name, gpx))
+        .collect();
+    let differences = match activities.split_first() {
+        None => Vec::new(),
+        Some(((_, reference), others)) => others
+            .iter()
+            .map(|(name, other)| differ(name, reference.trkpts(), other.trkpts()))
+            .collect(),
+    };
+
+    Report {
+        summaries,
+        differences,
+    }
+}
+
+fn optional_meters(meters: Option<f64>) -> String {
+    meters.map_or_else(|| "-".to_string(), |m| format!("{m:.1} m"))
+}
+
+fn write_stats(f: &mut Formatter, label: &str, stats: &Option<Stats>, units: &str) -> fmt::Result {
+    match stats {
+        None => writeln!(f, "    {label:<10} -"),
+        Some(s) => writeln!(
+            f,
+            "    {label:<10} mean {:+.2}{units}, mean |diff| {:.2}{units}, max |diff| {:.2}{units} ({} points)",
+            s.mean, s.mean_abs, s.max_abs, s.count
+        ),
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for s in &self.summaries {
+            let local = |time: Option<DateTime<Utc>>| {
+                time.map_or_else(
+                    || "-".to_string(),
+                    |t| t.with_timezone(crate::tz()).naive_local().to_string(),
+                )
+            };
+            writeln!(
+                f,
+                "{}: {} points from {} to {}; {} with position, {} with elevation, {} with HR",
+                s.name,
+                s.points,
+                local(s.start),
+                local(s.stop),
+                s.with_position,
+                s.with_elevation,
+                s.with_heart_rate
+            )?;
+            writeln!(
+                f,
+                "    distance reported {}, computed from positions {}",
+                optional_meters(s.reported_meters),
+                optional_meters(s.computed_meters)
+            )?;
+        }
+
+        if let Some(reference) = self.summaries.first() {
+            for d in &self.differences {
+                writeln!(
+                    f,
+                    "{} vs {} ({} of {} reference points overlap):",
+                    d.name, reference.name, d.matched, reference.points
+                )?;
+                write_stats(f, "position", &d.position_meters, " m")?;
+                write_stats(f, "elevation", &d.elevation_meters, " m")?;
+                write_stats(f, "HR", &d.heart_rate, " bpm")?;
+                write_stats(f, "distance", &d.distance_meters, " m")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trkpt(seconds: i64, elevation_meters: f64) -> Trkpt {
+        Trkpt::at_timestamp(seconds).with_elevation(elevation_meters)
+    }
+
+    #[test]
+    fn test_value_at() {
+        let trkpts = [trkpt(0, 10.0), trkpt(2, 20.0), trkpt(10, 0.0)];
+        let elevation = |t: &Trkpt| t.elevation_meters;
+        let at = |seconds| {
+            value_at(
+                &trkpts,
+                DateTime::from_timestamp(seconds, 0).unwrap(),
+                elevation,
+            )
+        };
+
+        assert_eq!(Some(10.0), at(0));
+        assert_eq!(Some(15.0), at(1));
+        assert_eq!(Some(20.0), at(2));
+        assert_eq!(None, at(5)); // too far between samples
+        assert_eq!(None, at(11));
+    }
+
+    #[test]
+    fn test_compare() {
+        let reference = Gpx::from(vec![trkpt(0, 10.0), trkpt(1, 11.0), trkpt(2, 12.0)]);
+        let other = Gpx::from(vec![trkpt(1, 12.0), trkpt(2, 14.0), trkpt(3, 16.0)]);
+        let report = compare(&[("a".to_string(), reference), ("b".to_string(), other)]);
+        let difference = &report.differences[0];
+
+        assert_eq!(2, difference.matched);
+        assert_eq!(
+            Some(Stats {
+                count: 2,
+                mean: 1.5,
+                mean_abs: 1.5,
+                max_abs: 2.0
+            }),
+            difference.elevation_meters
+        );
+        assert_eq!(None, difference.heart_rate);
+    }
+}
@@ -0,0 +1,590 @@
+// Critical speed: the two-parameter model in which a runner can cover
+// D′ meters plus CS meters per second for however long they run all out,
+// i.e., meters = CS·seconds + D′, fit by least squares to efforts of
+// different durations.
+//
+// CS is roughly the speed that can be held for half an hour or so and D′
+// the distance's worth of work that can be done faster than that before
+// running out.  The model only holds for efforts of a couple of minutes to
+// about twenty, so efforts outside MIN_SECONDS..=MAX_SECONDS are ignored,
+// and it's only as good as the efforts were hard.
+//
+// The D′ balance is what's left of D′ as an activity goes on (Skiba's
+// model): running faster than CS spends it meter for meter and running
+// slower recovers it, quickly when it's nearly gone and more slowly as it
+// fills back up, at a rate proportional to how far below CS the pace is.
+// That's also what picks interval paces: the speed at which a session's
+// reps, with their recoveries, use up D′ just as the last one ends.
+
+use {
+    crate::{
+        best::BestEffort,
+        gpx::{Gpx, Interval, IntervalReport, METERS_PER_MILE, seconds_between},
+    },
+    chrono::{DateTime, Utc},
+    digital_duration_nom::duration::Duration,
+    std::fmt::{self, Display, Formatter},
+};
+
+pub(crate) const MIN_SECONDS: f64 = 60.0;
+pub(crate) const MAX_SECONDS: f64 = 20.0 * 60.0;
+// Fits need the longest effort to be at least this many times the shortest.
+pub(crate) const MIN_SPAN: f64 = 2.0;
+
+// How far and how long, e.g., an interval or a best effort
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Performance {
+    pub meters: f64,
+    pub seconds: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CriticalSpeed {
+    pub meters_per_second: f64,
+    pub d_prime: f64,
+    // None when CS and D′ were given rather than fit
+    fit: Option<LinearFit>,
+}
+
+// What the intervals in a report did to the D′ balance and the pace that
+// would have used it all
+#[derive(Debug)]
+pub struct BalanceReport {
+    origin: Option<DateTime<Utc>>,
+    pub critical_speed: CriticalSpeed,
+    // Each interval's start, speed and the balance at its stop
+    pub intervals: Vec<(DateTime<Utc>, f64, f64)>,
+    // When the balance was lowest and what it was
+    pub lowest: Option<(DateTime<Utc>, f64)>,
+    pub suggestion: Option<Suggestion>,
+}
+
+// The pace at which a report's reps, with their rests, would have used
+// all of D′
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Suggestion {
+    // The average rep and rest, in seconds
+    pub work: f64,
+    pub rest: f64,
+    // Measured over the report's rests
+    pub rest_speed: f64,
+    pub count: usize,
+    // From interval_speed
+    pub speed: f64,
+}
+
+// y = slope·x + intercept, plus what's needed for prediction intervals
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LinearFit {
+    pub slope: f64,
+    pub intercept: f64,
+    pub n: usize,
+    mean_x: f64,
+    // Σ(x - mean_x)², or None when the slope was given rather than fit
+    sxx: Option<f64>,
+    // The residuals' standard deviation; None without enough points
+    sigma: Option<f64>,
+}
+
+impl Performance {
+    // pace is seconds per mile.
+    pub fn from_pace(pace: f64, seconds: f64) -> Self {
+        Performance {
+            meters: seconds / pace * METERS_PER_MILE,
+            seconds,
+        }
+    }
+
+    pub fn meters_per_second(&self) -> f64 {
+        self.meters / self.seconds
+    }
+}
+
+impl From<&Interval> for Performance {
+    fn from(interval: &Interval) -> Self {
+        let seconds = seconds_between(interval.start(), interval.stop());
+        Performance {
+            meters: interval.meters_per_second() * seconds,
+            seconds,
+        }
+    }
+}
+
+impl From<&BestEffort> for Performance {
+    fn from(best: &BestEffort) -> Self {
+        Performance {
+            meters: best.meters(),
+            seconds: best.seconds(),
+        }
+    }
+}
+
+// Student's t for a two-sided 95% interval with df degrees of freedom
+fn t_95(df: usize) -> f64 {
+    const SMALL: [f64; 4] = [12.706, 4.303, 3.182, 2.776];
+    match df {
+        0 => f64::INFINITY,
+        1..=4 => SMALL[df - 1],
+        _ => {
+            // Cornish–Fisher, good to a few thousandths from here on
+            let (z, df) = (1.959_964_f64, df as f64);
+            z + (z.powi(3) + z) / (4.0 * df)
+                + (5.0 * z.powi(5) + 16.0 * z.powi(3) + 3.0 * z) / (96.0 * df * df)
+        }
+    }
+}
+
+// True when the largest of values is at least MIN_SPAN times the smallest
+pub(crate) fn spans(values: impl Iterator<Item = f64> + Clone) -> bool {
+    let min = values.clone().fold(f64::INFINITY, f64::min);
+    let max = values.fold(0.0, f64::max);
+    min > 0.0 && max >= MIN_SPAN * min
+}
+
+impl LinearFit {
+    // Least squares; None for fewer than two distinct xs.
+    pub(crate) fn new(points: &[(f64, f64)]) -> Option<Self> {
+        let n = points.len();
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n as f64;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n as f64;
+        let sxx = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+        if n < 2 || sxx <= 0.0 {
+            return None;
+        }
+        let sxy = points
+            .iter()
+            .map(|p| (p.0 - mean_x) * (p.1 - mean_y))
+            .sum::<f64>();
+        let slope = sxy / sxx;
+        Some(Self::with_residuals(
+            points,
+            slope,
+            mean_y - slope * mean_x,
+            Some(sxx),
+        ))
+    }
+
+    // With the slope given, only the intercept is fit.
+    pub(crate) fn with_slope(points: &[(f64, f64)], slope: f64) -> Option<Self> {
+        if points.is_empty() {
+            return None;
+        }
+        let n = points.len() as f64;
+        let intercept = points.iter().map(|p| p.1 - slope * p.0).sum::<f64>() / n;
+        Some(Self::with_residuals(points, slope, intercept, None))
+    }
+
+    fn with_residuals(points: &[(f64, f64)], slope: f64, intercept: f64, sxx: Option<f64>) -> Self {
+        let n = points.len();
+        let parameters = if sxx.is_some() { 2 } else { 1 };
+        let sigma = (n > parameters).then(|| {
+            let squares = points
+                .iter()
+                .map(|p| (p.1 - slope * p.0 - intercept).powi(2))
+                .sum::<f64>();
+            (squares / (n - parameters) as f64).sqrt()
+        });
+        LinearFit {
+            slope,
+            intercept,
+            n,
+            mean_x: points.iter().map(|p| p.0).sum::<f64>() / n as f64,
+            sxx,
+            sigma,
+        }
+    }
+
+    pub(crate) fn y(&self, x: f64) -> f64 {
+        self.slope * x + self.intercept
+    }
+
+    // Half the width of the 95% prediction interval for a new y at x
+    pub(crate) fn margin(&self, x: f64) -> Option<f64> {
+        let sigma = self.sigma?;
+        let leverage = self.sxx.map_or(0.0, |sxx| (x - self.mean_x).powi(2) / sxx);
+        let parameters = if self.sxx.is_some() { 2 } else { 1 };
+        Some(t_95(self.n - parameters) * sigma * (1.0 + 1.0 / self.n as f64 + leverage).sqrt())
+    }
+}
+
+impl CriticalSpeed {
+    // None unless there are efforts of different enough durations that add
+    // up to a positive CS and D′.
+    pub fn fit(performances: &[Performance]) -> Option<Self> {
+        let points = performances
+            .iter()
+            .filter(|p| (MIN_SECONDS..=MAX_SECONDS).contains(&p.seconds))
+            .map(|p| (p.seconds, p.meters))
+            .collect::<Vec<_>>();
+        if !spans(points.iter().map(|p| p.0)) {
+            return None;
+        }
+        let fit = LinearFit::new(&points)?;
+        (fit.slope > 0.0 && fit.intercept >= 0.0).then_some(CriticalSpeed {
+            meters_per_second: fit.slope,
+            d_prime: fit.intercept,
+            fit: Some(fit),
+        })
+    }
+
+    pub fn new(meters_per_second: f64, d_prime: f64) -> Self {
+        CriticalSpeed {
+            meters_per_second,
+            d_prime,
+            fit: None,
+        }
+    }
+
+    // How many performances the fit used
+    pub fn efforts(&self) -> usize {
+        self.fit.as_ref().map_or(0, |fit| fit.n)
+    }
+
+    // Per mile
+    pub fn pace(&self) -> f64 {
+        METERS_PER_MILE / self.meters_per_second
+    }
+
+    // The time to run meters all out and, with enough efforts, the 95%
+    // range.  None for distances within D′.
+    pub fn seconds_for(&self, meters: f64) -> Option<(f64, Option<(f64, f64)>)> {
+        if meters <= self.d_prime {
+            return None;
+        }
+        let seconds = (meters - self.d_prime) / self.meters_per_second;
+        // Covering more or less in that time is finishing sooner or later.
+        let range = self
+            .fit
+            .as_ref()
+            .and_then(|fit| fit.margin(seconds))
+            .map(|margin| {
+                let change = margin / self.meters_per_second;
+                ((seconds - change).max(0.0), seconds + change)
+            });
+        Some((seconds, range))
+    }
+
+    // The balance after seconds at meters_per_second, starting from balance
+    pub fn balance_after(&self, balance: f64, meters_per_second: f64, seconds: f64) -> f64 {
+        let below = self.meters_per_second - meters_per_second;
+        if below <= 0.0 || self.d_prime <= 0.0 {
+            balance - below.abs() * seconds
+        } else {
+            self.d_prime - (self.d_prime - balance) * (-below * seconds / self.d_prime).exp()
+        }
+    }
+
+    // The speed at which count reps of work seconds, each but the last
+    // followed by rest seconds at rest_speed, use up D′ exactly.  None when
+    // the rests alone would use up more.
+    pub fn interval_speed(
+        &self,
+        work: f64,
+        rest: f64,
+        count: usize,
+        rest_speed: f64,
+    ) -> Option<f64> {
+        let below = self.meters_per_second - rest_speed;
+        if below <= 0.0 || self.d_prime <= 0.0 {
+            // Rests at or above CS spend D′ meter for meter, and without
+            // any D′ rests below CS have nothing to recover.
+            let rests = count.saturating_sub(1) as f64 * (-below * rest).max(0.0);
+            let left = self.d_prime - rests;
+            return (left >= 0.0).then(|| self.meters_per_second + left / count as f64 / work);
+        }
+        // What's left of each rep's deficit after a rest
+        let kept = (-below * rest / self.d_prime).exp();
+        // The deficits add up to deficit·(1 + kept + kept² + ...).
+        let reps = if kept < 1.0 {
+            (1.0 - kept.powi(count as i32)) / (1.0 - kept)
+        } else {
+            count as f64
+        };
+        Some(self.meters_per_second + self.d_prime / reps / work)
+    }
+}
+
+// The D′ balance at each trkpt with a speed, starting full.
+pub fn d_prime_balance(gpx: &Gpx, critical_speed: &CriticalSpeed) -> Vec<(DateTime<Utc>, f64)> {
+    let trkpts = gpx.trkpts();
+    let mut balance = critical_speed.d_prime;
+    let mut balances = Vec::with_capacity(trkpts.len());
+
+    for pair in trkpts.windows(2) {
+        if let Some(meters_per_second) = pair[1].meters_per_second {
+            let seconds = seconds_between(pair[0].time, pair[1].time);
+            balance = critical_speed.balance_after(balance, meters_per_second, seconds);
+            balances.push((pair[1].time, balance));
+        }
+    }
+    balances
+}
+
+impl BalanceReport {
+    // The suggestion assumes the rests are jogged as fast as the report's
+    // were.
+    pub fn new(gpx: &Gpx, report: &IntervalReport, critical_speed: CriticalSpeed) -> Self {
+        let balances = d_prime_balance(gpx, &critical_speed);
+        let balance_at = |when: DateTime<Utc>| {
+            let after = balances.partition_point(|&(time, _)| time <= when);
+            after
+                .checked_sub(1)
+                .map_or(critical_speed.d_prime, |i| balances[i].1)
+        };
+        let intervals = report
+            .intervals
+            .iter()
+            .map(|i| (i.start(), i.meters_per_second(), balance_at(i.stop())))
+            .collect::<Vec<_>>();
+        let lowest = balances.iter().copied().min_by(|a, b| a.1.total_cmp(&b.1));
+
+        let count = report.intervals.len();
+        let suggestion = if count == 0 {
+            None
+        } else {
+            let work = report
+                .intervals
+                .iter()
+                .map(|i| seconds_between(i.start(), i.stop()))
+                .sum::<f64>()
+                / count as f64;
+            let rest = report
+                .intervals
+                .windows(2)
+                .map(|w| seconds_between(w[0].stop(), w[1].start()))
+                .sum::<f64>()
+                / (count - 1).max(1) as f64;
+            let (meters, seconds) = report
+                .intervals
+                .windows(2)
+                .filter_map(|w| gpx.interval_between(w[0].stop(), w[1].start()))
+                .map(|i| {
+                    let seconds = seconds_between(i.start(), i.stop());
+                    (i.meters_per_second() * seconds, seconds)
+                })
+                .fold((0.0, 0.0), |(m, s), (meters, seconds)| {
+                    (m + meters, s + seconds)
+                });
+            let rest_speed = if seconds > 0.0 { meters / seconds } else { 0.0 };
+            critical_speed
+                .interval_speed(work, rest, count, rest_speed)
+                .map(|speed| Suggestion {
+                    work,
+                    rest,
+                    rest_speed,
+                    count,
+                    speed,
+                })
+        };
+
+        BalanceReport {
+            origin: gpx.trkpts().first().map(|t| t.time),
+            critical_speed,
+            intervals,
+            lowest,
+            suggestion,
+        }
+    }
+}
+
+impl Display for CriticalSpeed {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Critical speed {:.2} m/s ({:.1}/mi), D′ {:.0} m",
+            self.meters_per_second,
+            Duration::from(self.pace()),
+            self.d_prime
+        )?;
+        if self.fit.is_some() {
+            write!(f, " (from {} efforts)", self.efforts())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for BalanceReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let cs = &self.critical_speed;
+        let elapsed = |when| Duration::from(seconds_between(self.origin.unwrap_or(when), when));
+        // Without any D′, there's no fraction of it left.
+        let percent = |balance: f64| {
+            if cs.d_prime > 0.0 {
+                format!("{:.0}%", 100.0 * balance / cs.d_prime)
+            } else {
+                "-".to_string()
+            }
+        };
+        let pace = |meters_per_second: f64| Duration::from(METERS_PER_MILE / meters_per_second);
+
+        writeln!(f, "{cs}")?;
+        if !self.intervals.is_empty() {
+            writeln!(f, "{:>9} {:>7} {:>9}", "Start", "Pace", "D′ left")?;
+        }
+        for &(start, speed, balance) in &self.intervals {
+            writeln!(
+                f,
+                "{:9.1} {:7.1} {:>9}",
+                elapsed(start),
+                pace(speed),
+                percent(balance)
+            )?;
+        }
+        if let Some((when, balance)) = self.lowest {
+            writeln!(
+                f,
+                "Lowest D′ balance {balance:.0} m ({}) at {:.1}",
+                percent(balance),
+                elapsed(when)
+            )?;
+        }
+        if let Some(Suggestion {
+            work,
+            rest,
+            rest_speed,
+            count,
+            speed,
+        }) = self.suggestion
+        {
+            write!(
+                f,
+                "{count}×{:.1} with {:.1} rests",
+                Duration::from(work),
+                Duration::from(rest)
+            )?;
+            if rest_speed > 0.0 {
+                write!(f, " at {:.1}/mi", pace(rest_speed))?;
+            }
+            writeln!(f, " uses all of D′ at {:.1}/mi", pace(speed))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::gpx::{IntervalDetectionConfig, Trkpt},
+    };
+
+    #[test]
+    fn test_fit() {
+        // 4 m/s and 200 m
+        let exact = [90.0, 180.0, 300.0, 600.0, 1200.0, 3600.0].map(|seconds| Performance {
+            meters: 4.0 * seconds + 200.0,
+            seconds,
+        });
+        let cs = CriticalSpeed::fit(&exact).unwrap();
+        assert!((cs.meters_per_second - 4.0).abs() < 1e-9);
+        assert!((cs.d_prime - 200.0).abs() < 1e-6);
+        // The hour is too long for the model.
+        assert_eq!(5, cs.efforts());
+        let (seconds, range) = cs.seconds_for(5000.0).unwrap();
+        assert!((seconds - 1200.0).abs() < 1e-6);
+        let (low, high) = range.unwrap();
+        assert!(high - low < 1e-3);
+        assert_eq!(None, cs.seconds_for(150.0));
+
+        // Noise widens the range.
+        let noisy = exact.map(|p| Performance {
+            meters: p.meters + if p.seconds < 500.0 { 20.0 } else { -20.0 },
+            ..p
+        });
+        let (_, range) = CriticalSpeed::fit(&noisy)
+            .unwrap()
+            .seconds_for(5000.0)
+            .unwrap();
+        let (low, high) = range.unwrap();
+        assert!(high - low > 10.0);
+
+        // Efforts that are all about the same length don't fit.
+        let same = [60.0, 61.0, 75.0].map(|seconds| Performance {
+            meters: 5.0 * seconds,
+            seconds,
+        });
+        assert_eq!(None, CriticalSpeed::fit(&same));
+    }
+
+    #[test]
+    fn test_balance() {
+        let cs = CriticalSpeed::new(4.0, 200.0);
+        // 50 s at 5 m/s spends 50 m and then 100 s at 3 m/s gets back
+        // 1 - e^-0.5 of it.
+        let spent = cs.balance_after(200.0, 5.0, 50.0);
+        assert!((spent - 150.0).abs() < 1e-9);
+        let recovered = cs.balance_after(spent, 3.0, 100.0);
+        assert!((recovered - (200.0 - 50.0 * (-0.5_f64).exp())).abs() < 1e-9);
+        assert_eq!(150.0, cs.balance_after(150.0, 4.0, 100.0));
+
+        // Running the suggested speed empties D′ on the last rep, whether
+        // the rests are below CS or not.
+        for rest_speed in [2.0, 4.0, 4.5] {
+            let speed = cs.interval_speed(60.0, 60.0, 6, rest_speed).unwrap();
+            let mut balance = cs.d_prime;
+            for rep in 0..6 {
+                if rep > 0 {
+                    balance = cs.balance_after(balance, rest_speed, 60.0);
+                }
+                balance = cs.balance_after(balance, speed, 60.0);
+                assert!(balance > -1e-9, "{rest_speed}");
+            }
+            assert!(balance.abs() < 1e-9, "{rest_speed}");
+        }
+        // Without rest, the reps are one long effort.
+        let speed = cs.interval_speed(60.0, 0.0, 5, 2.0).unwrap();
+        assert!((speed - (4.0 + 200.0 / 300.0)).abs() < 1e-9);
+        // Five minutes of rests at 5 m/s spend more than D′.
+        assert_eq!(None, cs.interval_speed(60.0, 60.0, 6, 5.0));
+
+        // Without D′, the reps can only be at CS.
+        let none = CriticalSpeed::new(4.0, 0.0);
+        assert_eq!(Some(4.0), none.interval_speed(60.0, 60.0, 6, 2.0));
+        assert_eq!(None, none.interval_speed(60.0, 60.0, 6, 4.5));
+    }
+
+    #[test]
+    fn test_balance_report() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        // Three minutes at 5 m/s, each followed by two at 2 m/s
+        let gpx = Gpx::from(
+            (0..=900)
+                .map(|second| {
+                    Trkpt::at(second).with_speed(if second % 300 <= 180 { 5.0 } else { 2.0 })
+                })
+                .collect::<Vec<_>>(),
+        );
+        let report = gpx
+            .interval_report(
+                std::time::Duration::from_secs(180),
+                std::time::Duration::from_secs(60),
+                3,
+                &IntervalDetectionConfig::default(),
+            )
+            .unwrap();
+        let cs = CriticalSpeed::new(4.0, 200.0);
+        let balance = BalanceReport::new(&gpx, &report, cs.clone());
+        let suggestion = balance.suggestion.unwrap();
+        assert_eq!(3, suggestion.count);
+        assert!((suggestion.rest_speed - 2.0).abs() < 0.05, "{suggestion:?}");
+        assert_eq!(
+            Some(suggestion.speed),
+            cs.interval_speed(
+                suggestion.work,
+                suggestion.rest,
+                suggestion.count,
+                suggestion.rest_speed
+            )
+        );
+
+        let none = BalanceReport::new(&gpx, &report, CriticalSpeed::new(4.0, 0.0)).to_string();
+        assert!(!none.contains("NaN") && !none.contains("inf"), "{none}");
+    }
+
+    #[test]
+    fn test_t_95() {
+        assert_eq!(12.706, t_95(1));
+        assert!((t_95(10) - 2.228).abs() < 0.005);
+        assert!((t_95(1000) - 1.962).abs() < 0.005);
+    }
+}
@@ -0,0 +1,339 @@
+// Figures out an interval workout's duration, rest and repetition count
+// from the speed series alone, so they don't have to be given up front.
+//
+// The speeds are resampled to one per second and split into segments of
+// roughly constant speed using PELT (Pruned Exact Linear Time) change-point
+// detection with a squared-error cost.  Neighboring segments whose speeds
+// are close are merged, which leaves an alternating pattern of faster and
+// slower blocks.  A block that's faster than both of its neighbors is a
+// candidate repetition.  The most common candidate duration (on a grid of,
+// e.g., five seconds) is the work duration and the longest chain of
+// candidates that start a whole number of periods apart gives the count.
+// Allowing multiples of the period means a rep that didn't stand out (say,
+// one that was uphill) doesn't break the chain.
+
+use {
+    crate::gpx::{Gpx, Trkpt},
+    std::time,
+};
+
+#[derive(Clone, Debug)]
+pub struct DetectionConfig {
+    // Durations and rests are rounded to multiples of this many seconds.
+    pub grid_seconds: u8,
+    pub min_segment_seconds: usize,
+    // Multiplies the usual 2σ²·ln(n) penalty for adding a change point.
+    pub penalty_factor: f64,
+    // Adjacent segments closer than this are considered the same effort.
+    pub merge_meters_per_second: f64,
+    // How far a rep's duration may stray from the most common one.
+    pub tolerance_seconds: f64,
+    // How far the spacing between reps may stray from the typical spacing,
+    // as a fraction of it.
+    pub period_tolerance: f64,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            grid_seconds: 5,
+            min_segment_seconds: 5,
+            penalty_factor: 2.0,
+            merge_meters_per_second: 0.4,
+            tolerance_seconds: 10.0,
+            period_tolerance: 0.15,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetectedWorkout {
+    pub duration: time::Duration,
+    pub rest: time::Duration,
+    pub count: usize,
+}
+
+// A block of seconds [start, stop) of the per-second series.
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    start: usize,
+    stop: usize,
+}
+
+pub(crate) struct PrefixSums {
+    sums: Vec<f64>,
+    squares: Vec<f64>,
+}
+
+impl PrefixSums {
+    pub(crate) fn new(series: &[f64]) -> Self {
+        let mut sums = Vec::with_capacity(series.len() + 1);
+        let mut squares = Vec::with_capacity(series.len() + 1);
+        let (mut sum, mut square) = (0.0, 0.0);
+        sums.push(sum);
+        squares.push(square);
+        for x in series {
+            sum += x;
+            square += x * x;
+            sums.push(sum);
+            squares.push(square);
+        }
+        PrefixSums { sums, squares }
+    }
+
+    // Mean of series[start..stop]
+    pub(crate) fn mean(&self, start: usize, stop: usize) -> f64 {
+        (self.sums[stop] - self.sums[start]) / (stop - start) as f64
+    }
+
+    // Sum of squared deviations from the block's mean
+    fn cost(&self, start: usize, stop: usize) -> f64 {
+        let sum = self.sums[stop] - self.sums[start];
+        self.squares[stop] - self.squares[start] - sum * sum / (stop - start) as f64
+    }
+}
+
+// Returns the (exclusive) end of every segment, so the last element is
+// always series.len().
+pub fn change_points(series: &[f64], penalty: f64, min_size: usize) -> Vec<usize> {
+    let n = series.len();
+    let min_size = min_size.max(1);
+    if n < 2 * min_size {
+        return vec![n];
+    }
+    let sums = PrefixSums::new(series);
+    let mut best = vec![0.0; n + 1];
+    let mut previous = vec![0; n + 1];
+    let mut candidates = vec![0];
+    best[0] = -penalty;
+
+    for stop in min_size..=n {
+        let (cost, start) = candidates
+            .iter()
+            .filter(|&&start| stop - start >= min_size)
+            .map(|&start| (best[start] + sums.cost(start, stop) + penalty, start))
+            .fold((f64::INFINITY, 0), |a, b| if b.0 < a.0 { b } else { a });
+        best[stop] = cost;
+        previous[stop] = start;
+        // Pruning: a start that can't beat the current best now never will.
+        candidates.retain(|&start| {
+            stop - start < min_size || best[start] + sums.cost(start, stop) <= cost
+        });
+        candidates.push(stop);
+    }
+
+    let mut ends = Vec::new();
+    let mut stop = n;
+    while stop > 0 {
+        ends.push(stop);
+        stop = previous[stop];
+    }
+    ends.reverse();
+    ends
+}
+
+// One speed per second from the first trkpt on, carrying the last known
+// speed forward across gaps.
+pub(crate) fn per_second_speeds(trkpts: &[Trkpt]) -> Vec<f64> {
+    let Some(first) = trkpts.first() else {
+        return Vec::new();
+    };
+    let Some(last) = trkpts.last() else {
+        return Vec::new();
+    };
+    let seconds = (last.time - first.time).num_seconds().max(0) as usize + 1;
+    let mut speeds = Vec::with_capacity(seconds);
+    let mut i = 0;
+    let mut speed = 0.0;
+
+    for second in 0..seconds {
+        let now = first.time + chrono::TimeDelta::seconds(second as i64);
+        while i < trkpts.len() && trkpts[i].time <= now {
+            if let Some(mps) = trkpts[i].meters_per_second {
+                speed = mps;
+            }
+            i += 1;
+        }
+        speeds.push(speed);
+    }
+    speeds
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+fn to_grid(seconds: f64, grid: f64) -> f64 {
+    ((seconds / grid).round() * grid).max(grid)
+}
+
+fn segments(speeds: &[f64], sums: &PrefixSums, config: &DetectionConfig) -> Vec<Block> {
+    // Estimate the noise from successive differences, which are mostly
+    // unaffected by the (relatively rare) changes in effort.
+    let n = speeds.len() as f64;
+    let variance = speeds
+        .windows(2)
+        .map(|w| (w[1] - w[0]).powi(2))
+        .sum::<f64>()
+        / (2.0 * (n - 1.0).max(1.0));
+    let penalty = config.penalty_factor * 2.0 * variance * n.ln();
+
+    let mut start = 0;
+    let mut blocks = change_points(speeds, penalty, config.min_segment_seconds)
+        .into_iter()
+        .map(|stop| {
+            let block = Block { start, stop };
+            start = stop;
+            block
+        })
+        .collect::<Vec<_>>();
+
+    // Repeatedly merge the closest pair of neighbors.
+    while blocks.len() > 1 {
+        let (i, difference) = blocks
+            .windows(2)
+            .map(|w| (sums.mean(w[0].start, w[0].stop) - sums.mean(w[1].start, w[1].stop)).abs())
+            .enumerate()
+            .fold((0, f64::INFINITY), |a, b| if b.1 < a.1 { b } else { a });
+        if difference >= config.merge_meters_per_second {
+            break;
+        }
+        blocks[i].stop = blocks[i + 1].stop;
+        blocks.remove(i + 1);
+    }
+    blocks
+}
+
+fn peaks(blocks: &[Block], sums: &PrefixSums) -> Vec<Block> {
+    blocks
+        .iter()
+        .enumerate()
+        .filter(|&(i, block)| {
+            let speed = sums.mean(block.start, block.stop);
+            let slower = |j: Option<usize>| {
+                j.and_then(|j| blocks.get(j))
+                    .is_none_or(|b| sums.mean(b.start, b.stop) < speed)
+            };
+            slower(i.checked_sub(1)) && slower(Some(i + 1))
+        })
+        .map(|(_, block)| *block)
+        .collect()
+}
+
+// The longest run of reps whose starts are (close to) whole multiples of
+// period apart, as (number of reps, per-rep periods observed).  The
+// tolerance applies per period, since error accumulates over missed reps.
+fn longest_chain(reps: &[Block], period: f64, tolerance: f64) -> (usize, Vec<f64>) {
+    let mut best = (0, Vec::new());
+    let mut current = (0, Vec::new());
+
+    for (i, rep) in reps.iter().enumerate() {
+        let step = i.checked_sub(1).and_then(|j| {
+            let gap = (rep.start - reps[j].start) as f64;
+            let multiple = (gap / period).round();
+            (multiple >= 1.0 && (gap / multiple - period).abs() <= tolerance)
+                .then_some((multiple, gap))
+        });
+        match step {
+            Some((multiple, gap)) => {
+                current.0 += multiple as usize;
+                current.1.push(gap / multiple);
+            }
+            None => current = (1, Vec::new()),
+        }
+        if current.0 > best.0 {
+            best = current.clone();
+        }
+    }
+    best
+}
+
+pub fn detect(gpx: &Gpx, config: &DetectionConfig) -> Option<DetectedWorkout> {
+    let speeds = per_second_speeds(gpx.trkpts());
+    let sums = PrefixSums::new(&speeds);
+    let grid = f64::from(config.grid_seconds.max(1));
+    let tolerance = config.tolerance_seconds;
+    let candidates = peaks(&segments(&speeds, &sums, config), &sums);
+    let length = |block: &Block| (block.stop - block.start) as f64;
+
+    // The work duration is the grid duration that the most candidates are
+    // close to.
+    let duration = candidates
+        .iter()
+        .map(|c| to_grid(length(c), grid))
+        .max_by_key(|&d| {
+            let close = candidates
+                .iter()
+                .filter(|c| (length(c) - d).abs() <= tolerance)
+                .count();
+            // Prefer shorter durations on ties
+            (close, -(d as i64))
+        })?;
+    let reps = candidates
+        .into_iter()
+        .filter(|c| (length(c) - duration).abs() <= tolerance)
+        .collect::<Vec<_>>();
+    let mut durations = reps.iter().map(length).collect::<Vec<_>>();
+    let duration = to_grid(median(&mut durations)?, grid);
+
+    // A first guess at the period comes from the gaps between reps that
+    // are next to each other, which are the shortest ones.
+    let gaps = reps
+        .windows(2)
+        .map(|w| (w[1].start - w[0].start) as f64)
+        .collect::<Vec<_>>();
+    let shortest = gaps.iter().copied().fold(f64::INFINITY, f64::min);
+    let mut adjacent = gaps
+        .into_iter()
+        .filter(|&gap| gap <= shortest * 1.5)
+        .collect::<Vec<_>>();
+    let period = median(&mut adjacent)?;
+
+    let (count, mut periods) = longest_chain(&reps, period, period * config.period_tolerance);
+    let period = to_grid(median(&mut periods)?, grid);
+    let rest = (period - duration).max(grid);
+
+    Some(DetectedWorkout {
+        duration: time::Duration::from_secs_f64(duration),
+        rest: time::Duration::from_secs_f64(rest),
+        count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_change_points() {
+        let series = [[1.0; 20], [3.0; 20], [1.0; 20]].concat();
+        assert_eq!(vec![20, 40, 60], change_points(&series, 1.0, 5));
+        assert_eq!(vec![60], change_points(&series, 1000.0, 5));
+    }
+
+    #[test]
+    fn test_detect() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let path = Path::new("assets/Move_2018_12_17_06_59_29_Running.kml");
+        let gpx = crate::misc::activity_from(path).unwrap();
+
+        assert_eq!(
+            Some(DetectedWorkout {
+                duration: time::Duration::from_secs(75),
+                rest: time::Duration::from_secs(30),
+                count: 13
+            }),
+            detect(&gpx, &DetectionConfig::default())
+        );
+    }
+}
@@ -0,0 +1,317 @@
+// Elevation smoothing and total ascent and descent.
+//
+// Recorded elevations (barometric or GPS) wander by a meter or more from
+// sample to sample, so summing every rise overstates the climbing, often by
+// a lot.  A filter smooths the profile first and then, as watches do, a
+// rise or fall only counts once it's at least hysteresis_meters from the
+// last elevation that counted.
+
+use {
+    crate::gpx::{Gpx, Trkpt, seconds_between},
+    chrono::{DateTime, Utc},
+    std::{
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ElevationFilter {
+    None,
+    // The median of the window samples centered on each one
+    Median {
+        window: usize,
+    },
+    // A random walk seen through noise: process_noise is the variance (m²)
+    // the true elevation gains each second and measurement_noise the
+    // variance of each reading.  Run forward and then backward (a
+    // Rauch–Tung–Striebel smoother), so it doesn't lag.
+    Kalman {
+        process_noise: f64,
+        measurement_noise: f64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct ElevationConfig {
+    pub filter: ElevationFilter,
+    pub hysteresis_meters: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Climbing {
+    pub ascent: f64,
+    pub descent: f64,
+}
+
+impl Default for ElevationConfig {
+    fn default() -> Self {
+        ElevationConfig {
+            filter: ElevationFilter::KALMAN,
+            hysteresis_meters: 1.0,
+        }
+    }
+}
+
+impl ElevationFilter {
+    pub const MEDIAN: Self = ElevationFilter::Median { window: 5 };
+    pub const KALMAN: Self = ElevationFilter::Kalman {
+        process_noise: 0.05,
+        measurement_noise: 1.0,
+    };
+}
+
+impl FromStr for ElevationFilter {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "none" => Ok(ElevationFilter::None),
+            "median" => Ok(ElevationFilter::MEDIAN),
+            "kalman" => Ok(ElevationFilter::KALMAN),
+            _ => Err(format!(
+                "unknown elevation filter \"{string}\" (expected none, median or kalman)"
+            )),
+        }
+    }
+}
+
+fn median(window: usize, elevations: &[f64]) -> Vec<f64> {
+    let n = elevations.len();
+    // The window shrinks near the ends, so it's always centered.
+    (0..n)
+        .map(|i| {
+            let half = (window / 2).min(i).min(n - 1 - i);
+            let mut nearby = elevations[i - half..=i + half].to_vec();
+            nearby.sort_unstable_by(f64::total_cmp);
+            nearby[nearby.len() / 2]
+        })
+        .collect()
+}
+
+fn kalman(
+    process_noise: f64,
+    measurement_noise: f64,
+    samples: &[(DateTime<Utc>, f64)],
+) -> Vec<f64> {
+    let Some(&(mut last_time, first)) = samples.first() else {
+        return Vec::new();
+    };
+    // The filtered estimates and variances, and the predicted ones that
+    // led to them
+    let mut estimates = Vec::with_capacity(samples.len());
+    let mut predictions = Vec::with_capacity(samples.len());
+    let (mut estimate, mut variance) = (first, measurement_noise);
+
+    for &(time, elevation) in samples {
+        let predicted = variance + process_noise * seconds_between(last_time, time);
+        let gain = predicted / (predicted + measurement_noise);
+        estimate += gain * (elevation - estimate);
+        variance = (1.0 - gain) * predicted;
+        estimates.push((estimate, variance));
+        predictions.push(predicted);
+        last_time = time;
+    }
+
+    let mut smoothed = vec![0.0; samples.len()];
+    let mut later = *estimates.last().unwrap();
+    for i in (0..samples.len()).rev() {
+        let (estimate, variance) = estimates[i];
+        if i + 1 < samples.len() {
+            let ratio = variance / predictions[i + 1];
+            later = (
+                estimate + ratio * (later.0 - estimate),
+                variance + ratio * ratio * (later.1 - predictions[i + 1]),
+            );
+        } else {
+            later = (estimate, variance);
+        }
+        smoothed[i] = later.0;
+    }
+    smoothed
+}
+
+// The elevations of samples, smoothed.
+pub fn smooth(filter: &ElevationFilter, samples: &[(DateTime<Utc>, f64)]) -> Vec<f64> {
+    let elevations = || samples.iter().map(|&(_, elevation)| elevation);
+    match *filter {
+        ElevationFilter::None => elevations().collect(),
+        ElevationFilter::Median { window } => median(window, &elevations().collect::<Vec<_>>()),
+        ElevationFilter::Kalman {
+            process_noise,
+            measurement_noise,
+        } => kalman(process_noise, measurement_noise, samples),
+    }
+}
+
+pub fn climbing(elevations: &[f64], hysteresis_meters: f64) -> Climbing {
+    let mut climbing = Climbing {
+        ascent: 0.0,
+        descent: 0.0,
+    };
+    let Some(mut counted) = elevations.first().copied() else {
+        return climbing;
+    };
+
+    for &elevation in elevations {
+        let change = elevation - counted;
+        if change >= hysteresis_meters {
+            climbing.ascent += change;
+            counted = elevation;
+        } else if -change >= hysteresis_meters {
+            climbing.descent -= change;
+            counted = elevation;
+        }
+    }
+    climbing
+}
+
+// The recorded elevations, which is what watches total.
+pub(crate) fn samples(trkpts: &[Trkpt]) -> Vec<(DateTime<Utc>, f64)> {
+    trkpts
+        .iter()
+        .filter_map(|t| t.elevation_meters.map(|elevation| (t.time, elevation)))
+        .collect()
+}
+
+// Replaces the recorded elevations and the vertical speeds with smoothed
+// ones.  The vertical speeds are smoothed as the elevations they add up to.
+pub(crate) fn smooth_trkpts(filter: &ElevationFilter, trkpts: &mut [Trkpt]) {
+    let smoothed = smooth(filter, &samples(trkpts));
+    for (trkpt, elevation) in trkpts
+        .iter_mut()
+        .filter(|t| t.elevation_meters.is_some())
+        .zip(smoothed)
+    {
+        trkpt.elevation_meters = Some(elevation);
+    }
+
+    let Some(first) = trkpts.first() else {
+        return;
+    };
+    let mut profile = vec![(first.time, 0.0)];
+    for trkpt in trkpts.iter() {
+        if let Some(vertical_mps) = trkpt.vertical_mps {
+            let &(last_time, last_elevation) = profile.last().unwrap();
+            let elevation = last_elevation + vertical_mps * seconds_between(last_time, trkpt.time);
+            profile.push((trkpt.time, elevation));
+        }
+    }
+    let smoothed = smooth(filter, &profile);
+    for (k, trkpt) in trkpts
+        .iter_mut()
+        .filter(|t| t.vertical_mps.is_some())
+        .enumerate()
+    {
+        let seconds = seconds_between(profile[k].0, profile[k + 1].0);
+        if seconds > 0.0 {
+            trkpt.vertical_mps = Some((smoothed[k + 1] - smoothed[k]) / seconds);
+        }
+    }
+}
+
+// None when the activity has no elevations.
+pub fn total_climbing(gpx: &Gpx, config: &ElevationConfig) -> Option<Climbing> {
+    let samples = samples(gpx.trkpts());
+    if samples.is_empty() {
+        return None;
+    }
+    let smoothed = smooth(&config.filter, &samples);
+    Some(climbing(&smoothed, config.hysteresis_meters))
+}
+
+impl Display for Climbing {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ascent: {:.0} m, descent: {:.0} m",
+            self.ascent, self.descent
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_climbing() {
+        let elevations = [100.0, 101.0, 100.0, 101.0, 105.0, 104.0, 108.0, 100.0];
+        assert_eq!(
+            Climbing {
+                ascent: 8.0,
+                descent: 8.0
+            },
+            climbing(&elevations, 2.0)
+        );
+        assert_eq!(
+            Climbing {
+                ascent: 10.0,
+                descent: 10.0
+            },
+            climbing(&elevations, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_smooth() {
+        let samples = [100.0, 101.0, 150.0, 102.0, 103.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &e)| (DateTime::from_timestamp(i as i64, 0).unwrap(), e))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![100.0, 101.0, 102.0, 103.0, 103.0],
+            smooth(&ElevationFilter::Median { window: 3 }, &samples)
+        );
+        let kalman = smooth(&ElevationFilter::KALMAN, &samples);
+        assert!(kalman[2] < 125.0);
+    }
+
+    #[test]
+    fn test_smooth_trkpts() {
+        // Climbing at 0.5 m/s, but with every other vertical speed off by
+        // 1 m/s one way or the other.
+        let mut trkpts = (0..100)
+            .map(|second| {
+                Trkpt::at(second)
+                    .with_speed(3.0)
+                    .with_elevation(1600.0 + 0.5 * second as f64)
+                    .with_vertical(if second % 2 == 0 { 1.5 } else { -0.5 })
+            })
+            .collect::<Vec<_>>();
+        smooth_trkpts(&ElevationFilter::MEDIAN, &mut trkpts);
+
+        assert_eq!(Some(1600.0), trkpts[0].elevation_meters);
+        assert_eq!(Some(1625.0), trkpts[50].elevation_meters);
+        assert!(trkpts[10..90].iter().all(|t| {
+            let vertical_mps = t.vertical_mps.unwrap();
+            (vertical_mps - 0.5).abs() < 0.5 + 1e-9 && vertical_mps > 0.0
+        }));
+    }
+
+    // Suunto's own totals for this activity were 195 m up and 203 m down.
+    #[test]
+    fn test_total_climbing() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let config = ElevationConfig::default();
+        for path in [
+            "assets/Move_2018_12_17_06_59_29_Running.kml",
+            "assets/Move_2018_12_17_06_59_29_Running.fit",
+        ] {
+            let gpx = crate::misc::activity_from(path.as_ref()).unwrap();
+            let raw = total_climbing(
+                &gpx,
+                &ElevationConfig {
+                    filter: ElevationFilter::None,
+                    hysteresis_meters: 0.0,
+                },
+            )
+            .unwrap();
+            let climbing = total_climbing(&gpx, &config).unwrap();
+            assert!(raw.ascent > 2.0 * climbing.ascent);
+            assert!((climbing.ascent - 195.0).abs() < 5.0, "{climbing}");
+            assert!((climbing.descent - 203.0).abs() < 5.0, "{climbing}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Tabular output for people who live in spreadsheets.
+//
+// Each activity becomes a Sheet.  A bunch of sheets can be written as a
+// single CSV (with the sheet name in the first column) or as an xlsx
+// workbook with one worksheet per sheet (see xlsx::write_workbook).
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Write},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Number(f64),
+    Text(String),
+    Empty,
+}
+
+impl From<f64> for Cell {
+    fn from(number: f64) -> Self {
+        Cell::Number(number)
+    }
+}
+
+impl From<Option<f64>> for Cell {
+    fn from(number: Option<f64>) -> Self {
+        number.map_or(Cell::Empty, Cell::Number)
+    }
+}
+
+impl From<Option<u8>> for Cell {
+    fn from(number: Option<u8>) -> Self {
+        number.map_or(Cell::Empty, |n| Cell::Number(n.into()))
+    }
+}
+
+impl From<String> for Cell {
+    fn from(text: String) -> Self {
+        Cell::Text(text)
+    }
+}
+
+impl Display for Cell {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Cell::Number(number) => write!(f, "{number}"),
+            Cell::Text(text) => write!(f, "{text}"),
+            Cell::Empty => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sheet {
+    pub name: String,
+    pub header: Vec<&'static str>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Sheet {
+    pub fn new(name: &str, header: Vec<&'static str>) -> Self {
+        Sheet {
+            name: name.to_string(),
+            header,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        self.rows.push(row);
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_csv_line<W: Write>(
+    writer: &mut W,
+    fields: impl Iterator<Item = String>,
+) -> io::Result<()> {
+    let line = fields
+        .map(|field| csv_field(&field))
+        .collect::<Vec<_>>()
+        .join(",");
+    writeln!(writer, "{line}")
+}
+
+// All the sheets are expected to share a header; the header of the first
+// sheet is the one that's written.
+pub fn write_csv<W: Write>(sheets: &[Sheet], mut writer: W) -> io::Result<()> {
+    let Some(first) = sheets.first() else {
+        return Ok(());
+    };
+
+    write_csv_line(
+        &mut writer,
+        std::iter::once("Activity".to_string()).chain(first.header.iter().map(|h| h.to_string())),
+    )?;
+    for sheet in sheets {
+        for row in &sheet.rows {
+            write_csv_line(
+                &mut writer,
+                std::iter::once(sheet.name.clone()).chain(row.iter().map(Cell::to_string)),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_csv() {
+        let mut sheet = Sheet::new("17 Dec, 2018", vec!["Pace", "Note"]);
+        sheet.push(vec![
+            Cell::Number(439.8),
+            Cell::Text("said \"hi\"".to_string()),
+        ]);
+        sheet.push(vec![Cell::Empty, Cell::Text("fine".to_string())]);
+        let mut output = Vec::new();
+        write_csv(&[sheet], &mut output).unwrap();
+
+        assert_eq!(
+            "Activity,Pace,Note\n\
+             \"17 Dec, 2018\",439.8,\"said \"\"hi\"\"\"\n\
+             \"17 Dec, 2018\",,fine\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
@@ -1,4 +1,184 @@
-pub struct Fit {}
+// Just enough of a FIT parser to pull the record messages (i.e., the
+// samples) out of an activity file.
+//
+// A FIT file is a header, a run of records and a trailing CRC.  Every
+// record starts with a one byte header.  Definition records describe the
+// layout of the data records that follow with the same local message type.
+// Data records either carry their own timestamp field or have a compressed
+// timestamp header that holds a five bit offset from the last timestamp
+// seen.
+
+use {
+    crate::gpx::{Gpx, Trkpt},
+    chrono::{DateTime, Utc},
+    nom::{
+        IResult, Parser,
+        bytes::complete::{tag, take},
+        multi::count,
+        number::Endianness,
+        number::complete::{le_u8, le_u16, le_u32, u16},
+    },
+    std::{
+        error,
+        fmt::{self, Display, Formatter},
+    },
+};
+
+// Seconds from the Unix epoch to the FIT epoch (1989-12-31T00:00:00Z).
+const FIT_EPOCH: i64 = 631_065_600;
+const RECORD: u16 = 20;
+const SEMICIRCLES_TO_DEGREES: f64 = 180.0 / 2_147_483_648.0;
+
+// Record field numbers
+const TIMESTAMP: u8 = 253;
+const POSITION_LAT: u8 = 0;
+const POSITION_LONG: u8 = 1;
+const ALTITUDE: u8 = 2;
+const HEART_RATE: u8 = 3;
+const CADENCE: u8 = 4;
+const DISTANCE: u8 = 5;
+const SPEED: u8 = 6;
+const VERTICAL_SPEED: u8 = 32;
+const ENHANCED_SPEED: u8 = 73;
+const ENHANCED_ALTITUDE: u8 = 78;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NotFit,
+    Truncated,
+    BadCrc,
+    UndefinedLocalMessage(u8),
+}
+
+impl error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::NotFit => write!(f, "Not a FIT file"),
+            Error::Truncated => write!(f, "Truncated FIT file"),
+            Error::BadCrc => write!(f, "FIT file fails its CRC check"),
+            Error::UndefinedLocalMessage(local) => {
+                write!(f, "Data for undefined local message type {local}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fit {
+    trkpts: Vec<Trkpt>,
+}
+
+struct Header {
+    size: u8,
+    data_size: u32,
+}
+
+#[derive(Clone, Copy)]
+struct FieldDefinition {
+    number: u8,
+    size: u8,
+    base_type: u8,
+}
+
+struct Definition {
+    endianness: Endianness,
+    global: u16,
+    fields: Vec<FieldDefinition>,
+    developer_size: usize,
+}
+
+fn header(input: &[u8]) -> IResult<&[u8], Header> {
+    let (input, size) = le_u8(input)?;
+    let (input, (_protocol, _profile, data_size, _)) =
+        (le_u8, le_u16, le_u32, tag(&b".FIT"[..])).parse(input)?;
+    // 14 byte headers end with a CRC of the first twelve bytes, which we
+    // don't bother with since the whole file has a CRC.
+    let (input, _) = take(size.saturating_sub(12))(input)?;
+
+    Ok((input, Header { size, data_size }))
+}
+
+fn field_definition(input: &[u8]) -> IResult<&[u8], FieldDefinition> {
+    let (input, (number, size, base_type)) = (le_u8, le_u8, le_u8).parse(input)?;
+
+    Ok((
+        input,
+        FieldDefinition {
+            number,
+            size,
+            base_type,
+        },
+    ))
+}
+
+fn definition(input: &[u8], has_developer_data: bool) -> IResult<&[u8], Definition> {
+    let (input, (_reserved, architecture)) = (le_u8, le_u8).parse(input)?;
+    let endianness = if architecture == 0 {
+        Endianness::Little
+    } else {
+        Endianness::Big
+    };
+    let (input, global) = u16(endianness)(input)?;
+    let (input, field_count) = le_u8(input)?;
+    let (input, fields) = count(field_definition, field_count.into()).parse(input)?;
+    let (input, developer_size) = if has_developer_data {
+        let (input, developer_count) = le_u8(input)?;
+        let (input, developer_fields) =
+            count(field_definition, developer_count.into()).parse(input)?;
+        let developer_size = developer_fields.iter().map(|f| usize::from(f.size)).sum();
+        (input, developer_size)
+    } else {
+        (input, 0)
+    };
+
+    Ok((
+        input,
+        Definition {
+            endianness,
+            global,
+            fields,
+            developer_size,
+        },
+    ))
+}
+
+// Decodes single-valued integer fields, returning None for FIT's "invalid"
+// values.  Strings, floats and arrays aren't needed for records, so they're
+// also None.
+fn value(bytes: &[u8], endianness: Endianness, base_type: u8) -> Option<i64> {
+    let raw = match (bytes.len(), endianness) {
+        (1, _) => u64::from(bytes[0]),
+        (2, Endianness::Big) => u16::from_be_bytes([bytes[0], bytes[1]]).into(),
+        (2, _) => u16::from_le_bytes([bytes[0], bytes[1]]).into(),
+        (4, Endianness::Big) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).into(),
+        (4, _) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).into(),
+        _ => return None,
+    };
+    let bits = bytes.len() * 8;
+    let all_ones = u64::MAX >> (64 - bits);
+
+    match base_type {
+        // enum, uint8, uint16, uint32, byte
+        0x00 | 0x02 | 0x84 | 0x86 | 0x0D => (raw != all_ones).then_some(raw as i64),
+        // sint8, sint16, sint32
+        0x01 | 0x83 | 0x85 => {
+            let max = all_ones >> 1;
+            (raw != max).then(|| {
+                let shift = 64 - bits;
+                ((raw << shift) as i64) >> shift
+            })
+        }
+        // uint8z, uint16z, uint32z
+        0x0A | 0x8B | 0x8C => (raw != 0).then_some(raw as i64),
+        _ => None,
+    }
+}
+
+fn time_from_fit(timestamp: u32) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(FIT_EPOCH + i64::from(timestamp), 0)
+}
 
 impl Fit {
     fn crc_get16(crc: u16, byte: u8) -> u16 {
@@ -20,6 +200,153 @@ impl Fit {
         data.iter()
             .fold(0, |crc, datum| Self::crc_get16(crc, *datum))
     }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let Ok((_, header)) = header(bytes) else {
+            return Err(Error::NotFit);
+        };
+        let data_start = usize::from(header.size);
+        let data_end = data_start + header.data_size as usize;
+        if bytes.len() < data_end + 2 {
+            return Err(Error::Truncated);
+        }
+        // Running the CRC over data that ends with its own CRC yields zero.
+        if Self::crc_calc16(&bytes[..data_end + 2]) != 0 {
+            return Err(Error::BadCrc);
+        }
+
+        Ok(Fit {
+            trkpts: Self::records(&bytes[data_start..data_end])?,
+        })
+    }
+
+    pub fn trkpts(&self) -> &[Trkpt] {
+        &self.trkpts
+    }
+
+    fn records(mut input: &[u8]) -> Result<Vec<Trkpt>, Error> {
+        let mut definitions: [Option<Definition>; 16] = Default::default();
+        let mut last_timestamp = None;
+        let mut trkpts = Vec::new();
+
+        while let Some((&record_header, rest)) = input.split_first() {
+            input = rest;
+            if record_header & 0x80 != 0 {
+                // Compressed timestamp header
+                let local = (record_header >> 5) & 0x3;
+                let offset = u32::from(record_header & 0x1F);
+                let timestamp = last_timestamp
+                    .map(|last: u32| last.wrapping_add(offset.wrapping_sub(last & 0x1F) & 0x1F));
+                last_timestamp = timestamp;
+                input = Self::data(
+                    input,
+                    &definitions,
+                    local,
+                    timestamp,
+                    &mut last_timestamp,
+                    &mut trkpts,
+                )?;
+            } else if record_header & 0x40 != 0 {
+                let local = record_header & 0xF;
+                let has_developer_data = record_header & 0x20 != 0;
+                let Ok((rest, definition)) = definition(input, has_developer_data) else {
+                    return Err(Error::Truncated);
+                };
+                definitions[usize::from(local)] = Some(definition);
+                input = rest;
+            } else {
+                let local = record_header & 0xF;
+                input = Self::data(
+                    input,
+                    &definitions,
+                    local,
+                    None,
+                    &mut last_timestamp,
+                    &mut trkpts,
+                )?;
+            }
+        }
+
+        Ok(trkpts)
+    }
+
+    fn data<'a>(
+        input: &'a [u8],
+        definitions: &[Option<Definition>],
+        local: u8,
+        compressed_timestamp: Option<u32>,
+        last_timestamp: &mut Option<u32>,
+        trkpts: &mut Vec<Trkpt>,
+    ) -> Result<&'a [u8], Error> {
+        let Some(definition) = &definitions[usize::from(local)] else {
+            return Err(Error::UndefinedLocalMessage(local));
+        };
+        let mut timestamp = compressed_timestamp;
+        let mut trkpt = Trkpt {
+            time: DateTime::<Utc>::MIN_UTC,
+            meters_per_second: None,
+            meters: None,
+            heart_rate: None,
+            cadence: None,
+            elevation_meters: None,
+            vertical_mps: None,
+            lat: None,
+            lon: None,
+        };
+
+        let mut input = input;
+        for field in &definition.fields {
+            let size = usize::from(field.size);
+            if input.len() < size {
+                return Err(Error::Truncated);
+            }
+            let (bytes, rest) = input.split_at(size);
+            input = rest;
+
+            let Some(value) = value(bytes, definition.endianness, field.base_type) else {
+                continue;
+            };
+            if field.number == TIMESTAMP {
+                timestamp = Some(value as u32);
+                *last_timestamp = timestamp;
+                continue;
+            }
+            if definition.global != RECORD {
+                continue;
+            }
+            let value = value as f64;
+            match field.number {
+                POSITION_LAT => trkpt.lat = Some(value * SEMICIRCLES_TO_DEGREES),
+                POSITION_LONG => trkpt.lon = Some(value * SEMICIRCLES_TO_DEGREES),
+                ALTITUDE | ENHANCED_ALTITUDE => trkpt.elevation_meters = Some(value / 5.0 - 500.0),
+                HEART_RATE => trkpt.heart_rate = Some(value as u8),
+                CADENCE => trkpt.cadence = Some(value as u8),
+                DISTANCE => trkpt.meters = Some(value / 100.0),
+                SPEED | ENHANCED_SPEED => trkpt.meters_per_second = Some(value / 1000.0),
+                VERTICAL_SPEED => trkpt.vertical_mps = Some(value / 1000.0),
+                _ => (),
+            }
+        }
+        if input.len() < definition.developer_size {
+            return Err(Error::Truncated);
+        }
+        let input = &input[definition.developer_size..];
+
+        if definition.global == RECORD
+            && let Some(time) = timestamp.and_then(time_from_fit)
+        {
+            trkpt.time = time;
+            trkpts.push(trkpt);
+        }
+
+        Ok(input)
+    }
+}
+
+impl From<Fit> for Gpx {
+    fn from(fit: Fit) -> Self {
+        Gpx::from(fit.trkpts)
+    }
 }
 
 #[test]
@@ -35,3 +362,36 @@ fn test_crc_calc16() {
     assert_eq!(0x3484, Fit::crc_calc16(&FED[..2]));
     assert_eq!(0xA6F5, Fit::crc_calc16(&FED));
 }
+
+#[test]
+fn test_value() {
+    assert_eq!(Some(0x1234), value(&[0x34, 0x12], Endianness::Little, 0x84));
+    assert_eq!(Some(0x1234), value(&[0x12, 0x34], Endianness::Big, 0x84));
+    assert_eq!(None, value(&[0xFF, 0xFF], Endianness::Little, 0x84));
+    assert_eq!(Some(-2), value(&[0xFE, 0xFF], Endianness::Little, 0x83));
+    assert_eq!(
+        None,
+        value(&[0xFF, 0xFF, 0xFF, 0x7F], Endianness::Little, 0x85)
+    );
+    assert_eq!(None, value(&[0], Endianness::Little, 0x0A));
+}
+
+#[test]
+fn test_records() {
+    let bytes = std::fs::read("assets/Move_2018_12_17_06_59_29_Running.fit").unwrap();
+    let fit = Fit::from_bytes(&bytes).unwrap();
+    let trkpts = fit.trkpts();
+
+    assert_eq!(
+        DateTime::<Utc>::from_timestamp(1_545_055_170, 0).unwrap(),
+        trkpts[0].time
+    );
+    assert!(trkpts.windows(2).all(|w| w[0].time <= w[1].time));
+    assert!(trkpts.iter().any(|t| t.lat.is_some()));
+    assert!(trkpts.iter().any(|t| t.heart_rate.is_some()));
+
+    let mut corrupt = bytes.clone();
+    corrupt[100] ^= 0xFF;
+    assert_eq!(Error::BadCrc, Fit::from_bytes(&corrupt).unwrap_err());
+    assert_eq!(Error::NotFit, Fit::from_bytes(b"not fit").unwrap_err());
+}
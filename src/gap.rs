@@ -0,0 +1,43 @@
+// Grade-adjusted pace: the speed on flat ground that would cost as much
+// energy as the actual speed on the actual grade.
+//
+// The cost comes from Minetti et al., "Energy cost of walking and running
+// at extreme uphill and downhill slopes" (2002), whose polynomial fit gives
+// the energy (J/kg) to run a meter at a grade from -45% to +45%.  Grades
+// outside that range are clamped to it.  The grade is taken to be the
+// vertical speed over the speed, since that's what our trackpoints record.
+
+const MAX_GRADE: f64 = 0.45;
+
+// J/kg/m
+fn cost(grade: f64) -> f64 {
+    let i = grade.clamp(-MAX_GRADE, MAX_GRADE);
+    ((((155.4 * i - 30.4) * i - 43.3) * i + 46.3) * i + 19.5) * i + 3.6
+}
+
+// What to multiply the speed by to get the grade-adjusted speed.  Standing
+// still (or not having a vertical speed) gets no adjustment.
+pub fn adjustment(meters_per_second: f64, vertical_mps: f64) -> f64 {
+    if meters_per_second <= 0.0 {
+        return 1.0;
+    }
+    cost(vertical_mps / meters_per_second) / cost(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjustment() {
+        assert_eq!(1.0, adjustment(3.0, 0.0));
+        assert_eq!(1.0, adjustment(0.0, 1.0));
+        // 10% up is about 66% more work and 10% down about 40% less.
+        assert!((adjustment(3.0, 0.3) - 1.6578).abs() < 1e-3);
+        assert!((adjustment(3.0, -0.3) - 0.5977).abs() < 1e-3);
+        // The curve's minimum is around -20%.
+        assert!(adjustment(3.0, -0.6) < adjustment(3.0, -0.3));
+        assert!(adjustment(3.0, -0.6) < adjustment(3.0, -0.9));
+        assert_eq!(adjustment(1.0, 1.0), adjustment(1.0, 2.0));
+    }
+}
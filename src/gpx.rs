@@ -1,50 +1,424 @@
+use crate::elevation::{self, ElevationFilter};
+use crate::export::{Cell, Sheet};
+use crate::gap;
+use crate::speed::{self, Correction, SpeedConfig};
 use chrono::{DateTime, TimeDelta, Utc};
 use digital_duration_nom::duration::Duration;
-use geo::{LineString, prelude::*};
 use ordered_float::NotNan;
 use roxmltree::Document;
 use roxmltree::Node;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
 use std::str::FromStr;
+use std::time;
 
-// TODO: figure out the interval duration looking for abrupt changes in
-//       speed.  Consider having a constant like 5 for the common factor
-//       that all intervals will have.
-
-const METERS_PER_MILE: f64 = 1609.344;
+pub(crate) const METERS_PER_MILE: f64 = 1609.344;
 const SECONDS_PER_MINUTE: f64 = 60.0;
+// Nobody runs fewer steps a minute than this or more strides.
+const MIN_STEPS_PER_MINUTE: f64 = 125.0;
 
 #[derive(Debug)]
 pub struct Gpx {
     trkpts: Vec<Trkpt>,
+    // The index of the first trkpt of each trkseg after the first
+    segment_starts: Vec<usize>,
 }
 
 #[derive(Debug)]
 pub struct Trkpt {
     pub time: DateTime<Utc>,
-    meters_per_second: Option<f64>,
-    #[allow(dead_code)]
-    meters: Option<f64>,
-    #[allow(dead_code)]
-    heart_rate: Option<u8>,
-    #[allow(dead_code)]
-    cadence: Option<u8>,
-    #[allow(dead_code)]
-    elevation_meters: Option<f64>,
-    vertical_mps: Option<f64>,
-    lat: f64,
-    lon: f64,
+    pub(crate) meters_per_second: Option<f64>,
+    pub(crate) meters: Option<f64>,
+    pub(crate) heart_rate: Option<u8>,
+    pub(crate) cadence: Option<u8>,
+    pub(crate) elevation_meters: Option<f64>,
+    pub(crate) vertical_mps: Option<f64>,
+    // Not every format has positions (e.g., Movescount xlsx samples).
+    pub(crate) lat: Option<f64>,
+    pub(crate) lon: Option<f64>,
 }
 
 #[derive(Debug, Clone)]
-struct Interval {
-    rank: NotNan<f64>, // meters_per_second, adjusted by elevation changes
+pub struct Interval {
+    rank: NotNan<f64>, // meters_per_second, or its grade-adjusted equivalent
     minutes_per_mile: f64,
+    gap_minutes_per_mile: f64,
     start: DateTime<Utc>,
     stop: DateTime<Utc>,
     gain: f64,
     loss: f64,
+    // Per minute, in whatever unit the trkpts have (see cadence_in_steps).
+    // None when no trkpt in the interval has a cadence.
+    cadence: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IntervalReport {
+    pub intervals: Vec<Interval>,
+    // Average pace with each interval weighted by its elapsed time.  None
+    // when no intervals were found.
+    pub average: Option<Duration>,
+}
+
+// The report is kept so the caller can still see what was found.
+#[derive(Debug)]
+pub struct IntervalCountError {
+    pub expected: usize,
+    pub report: IntervalReport,
+}
+
+impl Error for IntervalCountError {}
+
+// Why interval_report (or explain) came up empty-handed
+#[derive(Debug)]
+pub enum IntervalError {
+    // See IntervalDetectionConfig::validate
+    Config(String),
+    Count(IntervalCountError),
+}
+
+impl Error for IntervalError {}
+
+// The heuristics that pick which of the fastest windows were the intervals.
+#[derive(Clone, Debug)]
+pub struct IntervalDetectionConfig {
+    // Windows closer together than this fraction of the rest overlap, so
+    // only the faster of the two is kept.
+    pub rest_fraction: f64,
+    // Starting from the fastest interval, neighboring intervals must start
+    // within this multiple of interval plus rest of each other to be part
+    // of the same set.
+    pub span_slop: f64,
+    // ... and must be at least this fraction as fast as the fastest one.
+    pub min_rank_ratio: f64,
+    pub trim: Trim,
+    pub rank_by: RankBy,
+}
+
+// Whether intervals are ranked by their actual speed or by their
+// grade-adjusted speed, which favors hilly intervals run at the same effort.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RankBy {
+    Speed,
+    GradeAdjusted,
+}
+
+// How trkpt cadences were recorded: per leg (strides per minute, as Garmin
+// and Suunto record running cadence) or per step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CadenceUnit {
+    Auto,
+    Strides,
+    Steps,
+}
+
+// Which intervals to drop when a set has more than were asked for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trim {
+    SlowerEnd,
+    Start,
+    End,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Accepted,
+    // Slower than min_rank_ratio allows
+    TooSlow,
+    // Starts too long after (or before) its neighbor in the set
+    TooFar,
+    // Past a neighbor that was TooSlow or TooFar
+    OutsideSet,
+    Trimmed,
+}
+
+// A (slower) window that overlapped a candidate, so it wasn't one itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Precluded {
+    pub start: DateTime<Utc>,
+    pub stop: DateTime<Utc>,
+    pub rank: f64,
+}
+
+// A window that didn't overlap any faster one, the (slower) windows that
+// it precluded, in start order, and what happened to it.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub interval: Interval,
+    pub precluded: Vec<Precluded>,
+    pub verdict: Verdict,
+}
+
+// How the intervals were chosen, in start order.
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    origin: DateTime<Utc>,
+    // How many intervals were asked for
+    pub count: usize,
+    pub span_with_slop: time::Duration,
+    pub min_rank: f64,
+    pub candidates: Vec<Candidate>,
+}
+
+impl Default for IntervalDetectionConfig {
+    fn default() -> Self {
+        IntervalDetectionConfig {
+            rest_fraction: 0.5,
+            span_slop: 1.5,
+            min_rank_ratio: 0.70,
+            trim: Trim::SlowerEnd,
+            rank_by: RankBy::Speed,
+        }
+    }
+}
+
+impl IntervalDetectionConfig {
+    // Every setting has to be a finite number that's at least 0.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("rest fraction", self.rest_fraction),
+            ("span slop", self.span_slop),
+            ("minimum rank ratio", self.min_rank_ratio),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("{name} is {value}, but needs to be a number >= 0"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RankBy {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "speed" => Ok(RankBy::Speed),
+            "gap" => Ok(RankBy::GradeAdjusted),
+            _ => Err(format!(
+                "unknown ranking \"{string}\" (expected speed or gap)"
+            )),
+        }
+    }
+}
+
+impl RankBy {
+    fn rank(self, meters_per_second: f64, gap_meters_per_second: f64) -> f64 {
+        match self {
+            RankBy::Speed => meters_per_second,
+            RankBy::GradeAdjusted => gap_meters_per_second,
+        }
+    }
+}
+
+impl FromStr for CadenceUnit {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "auto" => Ok(CadenceUnit::Auto),
+            "strides" => Ok(CadenceUnit::Strides),
+            "steps" => Ok(CadenceUnit::Steps),
+            _ => Err(format!(
+                "unknown cadence unit \"{string}\" (expected auto, strides or steps)"
+            )),
+        }
+    }
+}
+
+impl FromStr for Trim {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "slower-end" => Ok(Trim::SlowerEnd),
+            "start" => Ok(Trim::Start),
+            "end" => Ok(Trim::End),
+            _ => Err(format!(
+                "unknown trim \"{string}\" (expected slower-end, start or end)"
+            )),
+        }
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let text = match self {
+            Verdict::Accepted => "accepted",
+            Verdict::TooSlow => "too slow",
+            Verdict::TooFar => "too far from its neighbor",
+            Verdict::OutsideSet => "outside the set",
+            Verdict::Trimmed => "trimmed",
+        };
+        f.write_str(text)
+    }
+}
+
+impl Explanation {
+    pub fn intervals(&self) -> Vec<Interval> {
+        self.candidates
+            .iter()
+            .filter(|c| c.verdict == Verdict::Accepted)
+            .map(|c| c.interval.clone())
+            .collect()
+    }
+
+    // The accepted intervals, which are an error unless there are as many
+    // as were asked for.
+    pub fn report(&self) -> Result<IntervalReport, IntervalCountError> {
+        let report = IntervalReport::new(self.intervals());
+
+        if report.intervals.len() == self.count {
+            Ok(report)
+        } else {
+            Err(IntervalCountError {
+                expected: self.count,
+                report,
+            })
+        }
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Set spacing up to {:.1}, minimum rank {:.6}",
+            Duration::from(self.span_with_slop.as_secs_f64()),
+            self.min_rank
+        )?;
+        for c in &self.candidates {
+            writeln!(
+                f,
+                "{:9.1} {:9.1} {:.6} {} (precluded {})",
+                elapsed_since(self.origin, c.interval.start),
+                elapsed_since(self.origin, c.interval.stop),
+                c.interval.rank,
+                c.verdict,
+                c.precluded.len()
+            )?;
+            for p in &c.precluded {
+                writeln!(
+                    f,
+                    "  {:9.1} {:9.1} {:.6} overlaps it",
+                    elapsed_since(self.origin, p.start),
+                    elapsed_since(self.origin, p.stop),
+                    p.rank
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn elapsed_since(origin: DateTime<Utc>, when: DateTime<Utc>) -> Duration {
+    let elapsed = when - origin;
+    Duration::new(
+        elapsed.num_seconds().try_into().unwrap(),
+        elapsed.subsec_nanos().try_into().unwrap(),
+    )
+}
+
+impl Display for IntervalError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            IntervalError::Config(e) => f.write_str(e),
+            IntervalError::Count(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<IntervalCountError> for IntervalError {
+    fn from(e: IntervalCountError) -> Self {
+        IntervalError::Count(e)
+    }
+}
+
+impl Display for IntervalCountError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Was told to find {} intervals, but found {}",
+            self.expected,
+            self.report.intervals.len()
+        )
+    }
+}
+
+impl Interval {
+    pub fn rank(&self) -> f64 {
+        self.rank.into_inner()
+    }
+
+    // Per mile
+    pub fn pace(&self) -> Duration {
+        Duration::from(self.minutes_per_mile * SECONDS_PER_MINUTE)
+    }
+
+    // Grade-adjusted, per mile
+    pub fn gap_pace(&self) -> Duration {
+        Duration::from(self.gap_minutes_per_mile * SECONDS_PER_MINUTE)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from(seconds_between(self.start, self.stop))
+    }
+
+    pub fn start(&self) -> DateTime<Utc> {
+        self.start
+    }
+
+    pub fn stop(&self) -> DateTime<Utc> {
+        self.stop
+    }
+
+    // Meters
+    pub fn gain(&self) -> f64 {
+        self.gain
+    }
+
+    // Meters
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+
+    pub fn meters_per_second(&self) -> f64 {
+        METERS_PER_MILE / SECONDS_PER_MINUTE / self.minutes_per_mile
+    }
+
+    pub fn cadence(&self) -> Option<f64> {
+        self.cadence
+    }
+
+    // Meters per step (what watches call stride length), assuming the
+    // cadence is in steps.
+    pub fn stride_length(&self) -> Option<f64> {
+        self.cadence
+            .filter(|&cadence| cadence > 0.0)
+            .map(|cadence| self.meters_per_second() * SECONDS_PER_MINUTE / cadence)
+    }
+}
+
+impl IntervalReport {
+    pub(crate) fn new(intervals: Vec<Interval>) -> Self {
+        // TODO: document total_pace_durations
+        let mut total_pace_durations = Duration::new(0, 0);
+        let mut total_elapsed = Duration::new(0, 0);
+
+        for interval in &intervals {
+            let elapsed = interval.elapsed();
+            total_pace_durations += interval.pace() * elapsed;
+            total_elapsed += elapsed;
+        }
+
+        let average = match total_elapsed.as_secs() {
+            0 => None,
+            seconds => Some(total_pace_durations / seconds as u32),
+        };
+        IntervalReport { intervals, average }
+    }
 }
 
 impl Ord for Interval {
@@ -88,8 +462,8 @@ impl Trkpt {
         let mut cadence = None;
         let mut elevation_meters = None;
         let mut vertical_mps = None;
-        let lat = node.attribute("lat").unwrap().parse().unwrap();
-        let lon = node.attribute("lon").unwrap().parse().unwrap();
+        let lat = Some(node.attribute("lat").unwrap().parse().unwrap());
+        let lon = Some(node.attribute("lon").unwrap().parse().unwrap());
 
         for elem in node.descendants() {
             if elem.text().is_none() {
@@ -125,6 +499,269 @@ impl Trkpt {
     }
 }
 
+// For building activities in tests, e.g.,
+// Trkpt::at(second).with_speed(3.0).with_hr(150)
+#[cfg(test)]
+impl Trkpt {
+    // second seconds after 1_545_055_169 (the morning of December 17, 2018)
+    pub(crate) fn at(second: i64) -> Self {
+        Self::at_timestamp(1_545_055_169 + second)
+    }
+
+    // Nothing but a time, timestamp seconds after the Unix epoch
+    pub(crate) fn at_timestamp(timestamp: i64) -> Self {
+        Trkpt {
+            time: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            meters_per_second: None,
+            meters: None,
+            heart_rate: None,
+            cadence: None,
+            elevation_meters: None,
+            vertical_mps: None,
+            lat: None,
+            lon: None,
+        }
+    }
+
+    pub(crate) fn with_speed(self, meters_per_second: f64) -> Self {
+        Trkpt {
+            meters_per_second: Some(meters_per_second),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_hr(self, heart_rate: u8) -> Self {
+        Trkpt {
+            heart_rate: Some(heart_rate),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_elevation(self, elevation_meters: f64) -> Self {
+        Trkpt {
+            elevation_meters: Some(elevation_meters),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_vertical(self, vertical_mps: f64) -> Self {
+        Trkpt {
+            vertical_mps: Some(vertical_mps),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_position(self, lat: f64, lon: f64) -> Self {
+        Trkpt {
+            lat: Some(lat),
+            lon: Some(lon),
+            ..self
+        }
+    }
+}
+
+// See Gpx::running_totals
+#[derive(Clone, Copy, Debug)]
+struct Running {
+    index: usize,
+    time: DateTime<Utc>,
+    meters: f64,
+    // Grade-adjusted meters
+    gap_meters: f64,
+    gain: f64,
+    loss: f64,
+    // Cadence times minutes, i.e., steps (or strides), and the seconds they
+    // were taken over
+    steps: f64,
+    cadence_seconds: f64,
+}
+
+// duration × factor (from an IntervalDetectionConfig)
+fn scaled(duration: time::Duration, factor: f64) -> Result<TimeDelta, IntervalError> {
+    time::Duration::try_from_secs_f64(duration.as_secs_f64() * factor)
+        .map_err(|e| e.to_string())
+        .and_then(|scaled| TimeDelta::from_std(scaled).map_err(|e| e.to_string()))
+        .map_err(|e| IntervalError::Config(format!("scaling {duration:?} by {factor:e}: {e}")))
+}
+
+// To the nanosecond, like Gpx::f64_duration
+pub(crate) fn seconds_between(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    Gpx::f64_duration(&(to - from))
+}
+
+impl Running {
+    // The totals at time, part way from before to after (the next total)
+    fn at(before: &Running, after: &Running, time: DateTime<Utc>) -> Self {
+        let span = seconds_between(before.time, after.time);
+        let fraction = if span > 0.0 {
+            seconds_between(before.time, time) / span
+        } else {
+            1.0
+        };
+        let between = |before: f64, after: f64| before + (after - before) * fraction;
+        Running {
+            index: after.index,
+            time,
+            meters: between(before.meters, after.meters),
+            gap_meters: between(before.gap_meters, after.gap_meters),
+            gain: between(before.gain, after.gain),
+            loss: between(before.loss, after.loss),
+            steps: between(before.steps, after.steps),
+            cadence_seconds: between(before.cadence_seconds, after.cadence_seconds),
+        }
+    }
+}
+
+// Where a window starts or stops: at time, which is after totals[index - 1]
+// and no later than totals[index]
+#[derive(Clone, Copy, Debug)]
+struct Bound {
+    index: usize,
+    time: DateTime<Utc>,
+}
+
+// A window that might be an interval.  Only the windows that are kept get
+// the rest of what an Interval has worked out.
+#[derive(Clone, Copy, Debug)]
+struct Window {
+    rank: NotNan<f64>,
+    start: Bound,
+    stop: Bound,
+}
+
+// The fastest of the windows that are left, as a tournament: tree[i] holds
+// the faster of tree[2i] and tree[2i + 1], down to the leaves, tree[size..],
+// which hold the windows themselves.  Removing a run of windows only replays
+// the matches above it, so removing every window, a run at a time, is
+// linear.
+struct Tournament<'a> {
+    windows: &'a [Window],
+    size: usize,
+    tree: Vec<Option<usize>>,
+}
+
+impl Bound {
+    fn totals(&self, totals: &[Running]) -> Running {
+        let after = &totals[self.index];
+        if self.index == 0 || self.time >= after.time {
+            *after
+        } else {
+            Running::at(&totals[self.index - 1], after, self.time)
+        }
+    }
+}
+
+impl Window {
+    fn new(totals: &[Running], start: Bound, stop: Bound, rank_by: RankBy) -> Option<Self> {
+        let (meters_per_second, gap_meters_per_second) =
+            speeds(&start.totals(totals), &stop.totals(totals))?;
+        Some(Window {
+            rank: NotNan::new(rank_by.rank(meters_per_second, gap_meters_per_second)).ok()?,
+            start,
+            stop,
+        })
+    }
+
+    // Faster, or as fast and later, like Interval's Ord
+    fn faster_than(&self, other: &Window) -> bool {
+        (self.rank, self.start.time) > (other.rank, other.start.time)
+    }
+
+    fn fastest(windows: &[Window]) -> Option<&Window> {
+        windows
+            .iter()
+            .reduce(|a, b| if b.faster_than(a) { b } else { a })
+    }
+
+    fn interval(&self, totals: &[Running], rank_by: RankBy) -> Interval {
+        Interval::between(
+            &self.start.totals(totals),
+            &self.stop.totals(totals),
+            rank_by,
+        )
+        .unwrap()
+    }
+}
+
+impl<'a> Tournament<'a> {
+    fn new(windows: &'a [Window]) -> Self {
+        let size = windows.len().next_power_of_two();
+        let mut tree = vec![None; 2 * size];
+        for (i, leaf) in tree[size..size + windows.len()].iter_mut().enumerate() {
+            *leaf = Some(i);
+        }
+        let mut tournament = Tournament {
+            windows,
+            size,
+            tree,
+        };
+        for i in (1..size).rev() {
+            tournament.replay(i);
+        }
+        tournament
+    }
+
+    fn replay(&mut self, i: usize) {
+        self.tree[i] = match (self.tree[2 * i], self.tree[2 * i + 1]) {
+            (Some(a), Some(b)) if self.windows[b].faster_than(&self.windows[a]) => Some(b),
+            (a, b) => a.or(b),
+        };
+    }
+
+    fn fastest(&self) -> Option<usize> {
+        self.tree[1]
+    }
+
+    // Removes windows[range], returning the ones that hadn't already been.
+    fn remove(&mut self, range: Range<usize>) -> Vec<usize> {
+        let (mut from, mut to) = (self.size + range.start, self.size + range.end);
+        let removed = self.tree[from..to]
+            .iter_mut()
+            .filter_map(Option::take)
+            .collect();
+        while from > 1 && from < to {
+            from /= 2;
+            to = (to - 1) / 2 + 1;
+            for i in from..to {
+                self.replay(i);
+            }
+        }
+        removed
+    }
+}
+
+// The speed and grade-adjusted speed from start to stop; None when that
+// took no time.
+fn speeds(start: &Running, stop: &Running) -> Option<(f64, f64)> {
+    let seconds = seconds_between(start.time, stop.time);
+    (seconds > 0.0).then(|| {
+        (
+            (stop.meters - start.meters) / seconds,
+            (stop.gap_meters - start.gap_meters) / seconds,
+        )
+    })
+}
+
+impl Interval {
+    // Whatever was run from start to stop; None when that took no time.
+    fn between(start: &Running, stop: &Running, rank_by: RankBy) -> Option<Self> {
+        let (meters_per_second, gap_meters_per_second) = speeds(start, stop)?;
+        Some(Interval {
+            rank: NotNan::new(rank_by.rank(meters_per_second, gap_meters_per_second)).ok()?,
+            minutes_per_mile: Gpx::mpm_from_mps(meters_per_second),
+            gap_minutes_per_mile: Gpx::mpm_from_mps(gap_meters_per_second),
+            start: start.time,
+            stop: stop.time,
+            gain: stop.gain - start.gain,
+            loss: stop.loss - start.loss,
+            cadence: Gpx::average_cadence(
+                stop.steps - start.steps,
+                stop.cadence_seconds - start.cadence_seconds,
+            ),
+        })
+    }
+}
+
 impl Gpx {
     pub fn trkpt_iterator<'a>(doc: &'a Document) -> impl Iterator<Item = Trkpt> + 'a {
         doc.descendants()
@@ -138,98 +775,171 @@ impl Gpx {
             .map(|trkpt| Trkpt::from_node(&trkpt))
     }
 
-    fn trkpts(doc: &Document) -> Vec<Trkpt> {
-        Self::trkpt_iterator(doc).collect()
+    // The trkpts of every trkseg and where each trkseg after the first starts
+    fn segments_from(doc: &Document) -> (Vec<Trkpt>, Vec<usize>) {
+        let mut trkpts = Vec::new();
+        let mut segment_starts = Vec::new();
+
+        for trkseg in doc.descendants().filter(|n| n.has_tag_name("trkseg")) {
+            if !trkpts.is_empty() {
+                segment_starts.push(trkpts.len());
+            }
+            trkpts.extend(
+                trkseg
+                    .descendants()
+                    .filter(|n| n.has_tag_name("trkpt"))
+                    .map(|trkpt| Trkpt::from_node(&trkpt)),
+            );
+        }
+        (trkpts, segment_starts)
+    }
+
+    pub fn trkpts(&self) -> &[Trkpt] {
+        &self.trkpts
+    }
+
+    // In order
+    pub(crate) fn segment_starts(&self) -> &[usize] {
+        &self.segment_starts
     }
 
     fn f64_duration(duration: &TimeDelta) -> f64 {
-        duration.num_nanoseconds().unwrap() as f64 * 1e-9
+        duration.num_nanoseconds().unwrap() as f64 / 1e9
     }
 
     fn mpm_from_mps(meters_per_second: f64) -> f64 {
         METERS_PER_MILE / SECONDS_PER_MINUTE / meters_per_second
     }
 
-    fn potential_intervals(&self, duration: u8) -> BinaryHeap<Interval> {
-        let mut intervals = BinaryHeap::<Interval>::new();
-        let interval_duration = TimeDelta::try_seconds(i64::from(duration)).unwrap();
-
-        for offset in 0..self.trkpts.len() {
-            let mut window = self.trkpts[offset..].iter();
-            if let Some(trkpt) = window.next() {
-                let start = trkpt.time;
-                let mut meters = 0.0;
-                let mut duration = TimeDelta::try_seconds(0).unwrap();
-                let mut last_time = start;
-                let mut gain = 0.0;
-                let mut loss = 0.0;
-
-                while duration < interval_duration {
-                    if let Some(trkpt) = window.next() {
-                        if let Some(meters_per_second) = trkpt.meters_per_second {
-                            let vertical_mps = trkpt.vertical_mps.unwrap_or(0.0);
-                            let time = trkpt.time;
-                            let delta = time - last_time;
-                            let f64_delta = Self::f64_duration(&delta);
-                            meters += f64_delta * meters_per_second;
-                            let change = f64_delta * vertical_mps;
-                            if change.is_sign_negative() {
-                                loss -= change;
-                            } else {
-                                gain += change;
-                            }
-                            duration += delta;
-                            last_time = time;
-                        }
-                    } else {
-                        break;
-                    }
-                }
+    fn average_cadence(steps: f64, cadence_seconds: f64) -> Option<f64> {
+        (cadence_seconds > 0.0).then(|| steps / cadence_seconds * SECONDS_PER_MINUTE)
+    }
 
-                if duration >= interval_duration {
-                    let stop = start + duration;
-                    let f64_duration = Self::f64_duration(&duration);
-                    let meters_per_second = meters / f64_duration;
-                    let rank = NotNan::new(meters_per_second).unwrap();
-                    let minutes_per_mile = Self::mpm_from_mps(meters_per_second);
-                    intervals.push(Interval {
-                        rank,
-                        minutes_per_mile,
-                        start,
-                        stop,
-                        gain,
-                        loss,
-                    })
-                }
+    // Running totals at the first trkpt and at every later trkpt that has a
+    // speed.  Each speed covers the time since the previous such trkpt and
+    // so does each vertical speed and cadence, so the totals in between are
+    // on the line between the two (see Running::at).
+    fn running_totals(&self) -> Vec<Running> {
+        let mut totals = Vec::with_capacity(self.trkpts.len());
+        let Some(first) = self.trkpts.first() else {
+            return totals;
+        };
+        let mut last = Running {
+            index: 0,
+            time: first.time,
+            meters: 0.0,
+            gap_meters: 0.0,
+            gain: 0.0,
+            loss: 0.0,
+            steps: 0.0,
+            cadence_seconds: 0.0,
+        };
+
+        totals.push(last);
+        for (index, trkpt) in self.trkpts.iter().enumerate().skip(1) {
+            if let Some(meters_per_second) = trkpt.meters_per_second {
+                let vertical_mps = trkpt.vertical_mps.unwrap_or(0.0);
+                let f64_delta = seconds_between(last.time, trkpt.time);
+                let change = f64_delta * vertical_mps;
+                let adjustment = gap::adjustment(meters_per_second, vertical_mps);
+                let cadence = trkpt.cadence.map(f64::from);
+                last = Running {
+                    index,
+                    time: trkpt.time,
+                    meters: last.meters + f64_delta * meters_per_second,
+                    gap_meters: last.gap_meters + f64_delta * meters_per_second * adjustment,
+                    gain: last.gain + change.max(0.0),
+                    loss: last.loss - change.min(0.0),
+                    steps: last.steps + f64_delta * cadence.unwrap_or(0.0) / SECONDS_PER_MINUTE,
+                    cadence_seconds: last.cadence_seconds + cadence.map_or(0.0, |_| f64_delta),
+                };
+                totals.push(last);
             }
         }
-
-        intervals
+        totals
     }
 
-    fn precludes(intervals: &[Interval], interval: &Interval, rest: u8) -> bool {
-        intervals.iter().any(|i| {
-            interval.start < i.stop + std::time::Duration::from_secs((rest / 2).into())
-                && interval.stop > i.start
-        })
+    // Every window that starts at a trkpt and ends at the first trkpt with
+    // a speed that's at least duration later, in start order.  The window's
+    // bounds move forward together, so this is linear in the number of
+    // trkpts.
+    fn potential_intervals(
+        &self,
+        totals: &[Running],
+        duration: time::Duration,
+        rank_by: RankBy,
+    ) -> Vec<Window> {
+        let interval_duration = TimeDelta::from_std(duration).unwrap();
+        let mut windows = Vec::with_capacity(self.trkpts.len());
+        // totals[first] is the first total after the window's start and
+        // totals[last] is the one that ends the window.
+        let mut first = 0;
+        let mut last = 0;
+
+        for (offset, trkpt) in self.trkpts.iter().enumerate() {
+            let start = trkpt.time;
+            while first < totals.len() && totals[first].index <= offset {
+                first += 1;
+            }
+            last = last.max(first);
+            while last < totals.len() && totals[last].time - start < interval_duration {
+                last += 1;
+            }
+            let Some(end) = totals.get(last) else {
+                break;
+            };
+
+            // The first speed in the window only counts from the start,
+            // which isn't necessarily where the previous total was taken.
+            let start = Bound {
+                index: first,
+                time: start,
+            };
+            let stop = Bound {
+                index: last,
+                time: end.time,
+            };
+            windows.extend(Window::new(totals, start, stop, rank_by));
+        }
+        windows
     }
 
-    fn dump(&self, intervals: &[Interval], tod: bool) {
-        // TODO: document total_pace_durations
-        let mut total_pace_durations = Duration::new(0, 0);
-        let mut total_elapsed = Duration::new(0, 0);
+    // The interval from the first trkpt at or after start through the last
+    // one with a speed at or before stop, measured the same way as
+    // potential_intervals.
+    pub(crate) fn interval_between(
+        &self,
+        start: DateTime<Utc>,
+        stop: DateTime<Utc>,
+    ) -> Option<Interval> {
+        let totals = self.running_totals();
+        let offset = self.trkpts.partition_point(|t| t.time < start);
+        let start = self.trkpts.get(offset)?.time;
+        let first = totals.partition_point(|r| r.index <= offset);
+        let last = totals.partition_point(|r| r.time <= stop).checked_sub(1)?;
+        if last < first {
+            return None;
+        }
+        let start = Running::at(&totals[first - 1], &totals[first], start);
+        Interval::between(&start, &totals[last], RankBy::Speed)
+    }
 
-        for interval in intervals {
-            let seconds_per_mile = interval.minutes_per_mile * SECONDS_PER_MINUTE;
-            let pace = Duration::from(seconds_per_mile);
-            let elapsed = interval.stop - interval.start;
-            let elapsed = Duration::from(Self::f64_duration(&elapsed));
-            total_pace_durations += pace * elapsed;
-            total_elapsed += elapsed;
+    pub fn dump(&self, report: &IntervalReport, tod: bool) {
+        for interval in &report.intervals {
+            let pace = interval.pace();
+            let gap = interval.gap_pace();
+            let elapsed = interval.elapsed();
             let rank = interval.rank;
             let gain = interval.gain;
             let loss = interval.loss;
-            print!("{rank:.6} {elapsed:7} {pace:7.1} {gain:.5} {loss:.5} ");
+            let optional = |value: Option<f64>, precision: usize| {
+                value.map_or_else(|| "-".to_string(), |v| format!("{v:.precision$}"))
+            };
+            let cadence = optional(interval.cadence, 1);
+            let stride = optional(interval.stride_length(), 2);
+            print!(
+                "{rank:.6} {elapsed:7} {pace:7.1} {gap:7.1} {gain:.5} {loss:.5} {cadence:>5} {stride:>4} "
+            );
             if tod {
                 println!(
                     "{} {}",
@@ -245,142 +955,411 @@ impl Gpx {
             }
         }
 
-        let average = total_pace_durations / total_elapsed.as_secs() as u32;
-        println!("Average: {}", average);
+        if let Some(average) = report.average {
+            println!("Average: {}", average);
+        }
     }
 
     fn elapsed(&self, when: DateTime<Utc>) -> Duration {
-        let elapsed = when - self.trkpts[0].time;
-        Duration::new(
-            elapsed.num_seconds().try_into().unwrap(),
-            elapsed.subsec_nanos().try_into().unwrap(),
-        )
+        elapsed_since(self.trkpts[0].time, when)
     }
 
-    fn trim(intervals: &mut Vec<Interval>, count: u8) {
-        let mut len = intervals.len() as u8;
-
-        while len > count {
-            if intervals.first().unwrap().rank < intervals.last().unwrap().rank {
-                intervals.remove(0);
+    // Trims candidates[start_idx..stop_idx] down to count.
+    fn trim(
+        candidates: &mut [Candidate],
+        mut start_idx: usize,
+        mut stop_idx: usize,
+        count: usize,
+        trim: Trim,
+    ) {
+        while stop_idx - start_idx > count {
+            let from_start = match trim {
+                Trim::SlowerEnd => {
+                    candidates[start_idx].interval.rank < candidates[stop_idx - 1].interval.rank
+                }
+                Trim::Start => true,
+                Trim::End => false,
+            };
+            if from_start {
+                candidates[start_idx].verdict = Verdict::Trimmed;
+                start_idx += 1;
             } else {
-                intervals.pop();
+                stop_idx -= 1;
+                candidates[stop_idx].verdict = Verdict::Trimmed;
             }
-            len -= 1;
         }
     }
 
-    fn restrict_to_actual_intervals(intervals: &mut Vec<Interval>, span: f32, count: u8) {
-        let span_with_slop = TimeDelta::try_seconds((span * 1.50) as i64).unwrap();
-        let mut results = Vec::with_capacity(count as usize);
-        let best = intervals[0].clone();
+    // Sorts candidates by start and marks the ones that aren't part of the
+    // fastest candidate's set.  Returns the minimum rank used.
+    fn restrict_to_actual_intervals(
+        candidates: &mut [Candidate],
+        span_with_slop: TimeDelta,
+        count: usize,
+        config: &IntervalDetectionConfig,
+    ) -> f64 {
+        if candidates.is_empty() {
+            return 0.0;
+        }
+        let best = candidates[0].interval.clone();
 
-        intervals.sort_by_key(|i| i.start);
+        candidates.sort_by_key(|c| c.interval.start);
 
-        let mut start_idx = intervals
-            .iter()
-            .position(|interval| *interval == best)
-            .unwrap();
+        let mut start_idx = candidates.iter().position(|c| c.interval == best).unwrap();
         let mut stop_idx = start_idx + 1;
 
         let mut expected_start = best.start - span_with_slop;
-        let min_rank = NotNan::new(best.rank * 0.70).unwrap(); // TODO: document!
+        let min_rank = best.rank() * config.min_rank_ratio;
         while start_idx > 0
-            && intervals[start_idx - 1].start >= expected_start
-            && intervals[start_idx - 1].rank >= min_rank
+            && candidates[start_idx - 1].interval.start >= expected_start
+            && candidates[start_idx - 1].interval.rank() >= min_rank
         {
             start_idx -= 1;
-            expected_start = intervals[start_idx].start - span_with_slop;
+            expected_start = candidates[start_idx].interval.start - span_with_slop;
+        }
+        if start_idx > 0 {
+            let before = &mut candidates[start_idx - 1];
+            before.verdict = if before.interval.start < expected_start {
+                Verdict::TooFar
+            } else {
+                Verdict::TooSlow
+            };
+            for c in &mut candidates[..start_idx - 1] {
+                c.verdict = Verdict::OutsideSet;
+            }
         }
 
-        let max_stop_idx = intervals.len();
+        let max_stop_idx = candidates.len();
         expected_start = best.start + span_with_slop;
         while stop_idx < max_stop_idx
-            && intervals[stop_idx].start <= expected_start
-            && intervals[stop_idx].rank >= min_rank
+            && candidates[stop_idx].interval.start <= expected_start
+            && candidates[stop_idx].interval.rank() >= min_rank
         {
             stop_idx += 1;
             if stop_idx < max_stop_idx {
-                expected_start = intervals[stop_idx].start + span_with_slop;
+                expected_start = candidates[stop_idx].interval.start + span_with_slop;
+            }
+        }
+        if stop_idx < max_stop_idx {
+            let after = &mut candidates[stop_idx];
+            after.verdict = if after.interval.start > expected_start {
+                Verdict::TooFar
+            } else {
+                Verdict::TooSlow
+            };
+            for c in &mut candidates[stop_idx + 1..] {
+                c.verdict = Verdict::OutsideSet;
             }
         }
 
-        results.extend_from_slice(&intervals[start_idx..stop_idx]);
-        // Consider adjusting start_idx and stop_idx before extend_from_slice
-        // since adding the intervals to results and then trimming results is
-        // less efficient.  It certainly doesn't matter here, but still...
-        Self::trim(&mut results, count);
-        *intervals = results;
+        Self::trim(candidates, start_idx, stop_idx, count, config.trim);
+        min_rank
     }
 
-    fn intervals(&self, duration: u8, rest: u8, count: u8) -> Vec<Interval> {
-        let mut heap = self.potential_intervals(duration);
-        let mut intervals = Vec::new();
+    // Like potential_intervals, but each window covers meters rather than
+    // a fixed time.  The stop time, gain and loss are interpolated between
+    // the totals on either side of the point where the distance is reached.
+    fn potential_distance_intervals(
+        totals: &[Running],
+        meters: f64,
+        rank_by: RankBy,
+    ) -> Vec<Window> {
+        let mut windows = Vec::with_capacity(totals.len());
+        let mut after = 0;
 
-        while let Some(interval) = heap.pop() {
-            if !Self::precludes(&intervals, &interval, rest) {
-                intervals.push(interval);
+        for (i, start) in totals.iter().enumerate() {
+            let target = start.meters + meters;
+            after = after.max(i + 1);
+            while after < totals.len() && totals[after].meters < target {
+                after += 1;
             }
+            let Some(end) = totals.get(after) else {
+                break;
+            };
+            let before = &totals[after - 1];
+            let fraction = (target - before.meters) / (end.meters - before.meters);
+            let seconds = fraction * seconds_between(before.time, end.time);
+            let start = Bound {
+                index: i,
+                time: start.time,
+            };
+            let stop = Bound {
+                index: after,
+                time: before.time + TimeDelta::nanoseconds((seconds * 1e9).round() as i64),
+            };
+            windows.extend(Window::new(totals, start, stop, rank_by));
+        }
+        windows
+    }
+
+    // The fastest window of duration; None when the activity is shorter.
+    pub(crate) fn fastest_for(&self, duration: time::Duration) -> Option<Interval> {
+        let totals = self.running_totals();
+        let windows = self.potential_intervals(&totals, duration, RankBy::Speed);
+        Window::fastest(&windows).map(|w| w.interval(&totals, RankBy::Speed))
+    }
+
+    // The fastest stretch of meters; None when the activity is shorter.
+    pub(crate) fn fastest_over(&self, meters: f64) -> Option<Interval> {
+        let totals = self.running_totals();
+        let windows = Self::potential_distance_intervals(&totals, meters, RankBy::Speed);
+        Window::fastest(&windows).map(|w| w.interval(&totals, RankBy::Speed))
+    }
+
+    // Picks the fastest windows that don't overlap a faster one and then
+    // the count of them that look like they were run as one set.  The
+    // windows are in start order, and so are their stops, so the ones that
+    // a window overlaps are the run of them around it.  work is the
+    // (typical) length of an interval.
+    fn select(
+        &self,
+        totals: &[Running],
+        windows: &[Window],
+        work: time::Duration,
+        rest: time::Duration,
+        count: usize,
+        config: &IntervalDetectionConfig,
+    ) -> Result<Explanation, IntervalError> {
+        config.validate().map_err(IntervalError::Config)?;
+        let gap = scaled(rest, config.rest_fraction)?;
+        let span_with_slop = scaled(work + rest, config.span_slop)?;
+        let mut candidates = Vec::new();
+        let mut tournament = Tournament::new(windows);
+
+        while let Some(i) = tournament.fastest() {
+            let window = &windows[i];
+            let until = window.stop.time + gap;
+            let from = windows[..i].partition_point(|w| w.stop.time <= window.start.time);
+            let to = i + windows[i..].partition_point(|w| w.start.time < until);
+            let precluded = tournament
+                .remove(from..to)
+                .into_iter()
+                .filter(|&j| j != i)
+                .map(|j| Precluded {
+                    start: windows[j].start.time,
+                    stop: windows[j].stop.time,
+                    rank: windows[j].rank.into_inner(),
+                })
+                .collect::<Vec<_>>();
+            candidates.push(Candidate {
+                interval: window.interval(totals, config.rank_by),
+                precluded,
+                verdict: Verdict::Accepted,
+            });
         }
 
-        Self::restrict_to_actual_intervals(
-            &mut intervals,
-            f32::from(duration) + f32::from(rest),
+        let min_rank =
+            Self::restrict_to_actual_intervals(&mut candidates, span_with_slop, count, config);
+        Ok(Explanation {
+            origin: self.trkpts.first().map_or(DateTime::UNIX_EPOCH, |t| t.time),
             count,
+            span_with_slop: span_with_slop.to_std().unwrap_or_default(),
+            min_rank,
+            candidates,
+        })
+    }
+
+    // How interval_report chooses its intervals.  Explanation::report has
+    // the intervals themselves.
+    pub fn explain(
+        &self,
+        duration: time::Duration,
+        rest: time::Duration,
+        count: usize,
+        config: &IntervalDetectionConfig,
+    ) -> Result<Explanation, IntervalError> {
+        let totals = self.running_totals();
+        let windows = self.potential_intervals(&totals, duration, config.rank_by);
+        self.select(&totals, &windows, duration, rest, count, config)
+    }
+
+    // How distance_interval_report chooses its intervals
+    pub fn explain_distance(
+        &self,
+        meters: f64,
+        rest: time::Duration,
+        count: usize,
+        config: &IntervalDetectionConfig,
+    ) -> Result<Explanation, IntervalError> {
+        let totals = self.running_totals();
+        let windows = Self::potential_distance_intervals(&totals, meters, config.rank_by);
+        // The fastest repetition stands in for how long each one takes.
+        let work = Window::fastest(&windows).map_or(time::Duration::ZERO, |w| {
+            (w.stop.time - w.start.time).to_std().unwrap_or_default()
+        });
+        self.select(&totals, &windows, work, rest, count, config)
+    }
+
+    pub fn interval_report(
+        &self,
+        duration: time::Duration,
+        rest: time::Duration,
+        count: usize,
+        config: &IntervalDetectionConfig,
+    ) -> Result<IntervalReport, IntervalError> {
+        Ok(self.explain(duration, rest, count, config)?.report()?)
+    }
+
+    // Like interval_report, but each interval is meters long (e.g., 8×400 m
+    // on a track) and takes however long it took.
+    pub fn distance_interval_report(
+        &self,
+        meters: f64,
+        rest: time::Duration,
+        count: usize,
+        config: &IntervalDetectionConfig,
+    ) -> Result<IntervalReport, IntervalError> {
+        Ok(self
+            .explain_distance(meters, rest, count, config)?
+            .report()?)
+    }
+
+    // Like Explanation::report, but also prints the intervals, even when
+    // the count is wrong.
+    pub fn analyze(
+        &self,
+        explanation: &Explanation,
+        tod: bool,
+    ) -> Result<IntervalReport, IntervalCountError> {
+        let result = explanation.report();
+        match &result {
+            Ok(report) => self.dump(report, tod),
+            Err(e) => self.dump(&e.report, tod),
+        }
+        result
+    }
+
+    pub fn interval_sheet(&self, name: &str, report: &IntervalReport) -> Sheet {
+        let mut sheet = Sheet::new(
+            name,
+            vec![
+                "Rank",
+                "Elapsed [s]",
+                "Pace [s/mi]",
+                "GAP [s/mi]",
+                "Gain [m]",
+                "Loss [m]",
+                "Cadence [/min]",
+                "Stride [m]",
+                "Start",
+                "Stop",
+                "Start [s]",
+                "Stop [s]",
+            ],
+        );
+
+        for interval in &report.intervals {
+            let seconds_from_start = |when| seconds_between(self.trkpts[0].time, when).into();
+            sheet.push(vec![
+                interval.rank().into(),
+                seconds_between(interval.start, interval.stop).into(),
+                (interval.minutes_per_mile * SECONDS_PER_MINUTE).into(),
+                (interval.gap_minutes_per_mile * SECONDS_PER_MINUTE).into(),
+                interval.gain.into(),
+                interval.loss.into(),
+                interval.cadence.into(),
+                interval.stride_length().into(),
+                Self::local_time(interval.start),
+                Self::local_time(interval.stop),
+                seconds_from_start(interval.start),
+                seconds_from_start(interval.stop),
+            ]);
+        }
+        sheet
+    }
+
+    pub fn sample_sheet(&self, name: &str) -> Sheet {
+        let mut sheet = Sheet::new(
+            name,
+            vec![
+                "Time",
+                "Elapsed [s]",
+                "Latitude",
+                "Longitude",
+                "Elevation [m]",
+                "Distance [m]",
+                "Speed [m/s]",
+                "Vertical speed [m/s]",
+                "Heart rate [bpm]",
+                "Cadence",
+            ],
         );
-        intervals
+
+        for trkpt in &self.trkpts {
+            sheet.push(vec![
+                Self::local_time(trkpt.time),
+                seconds_between(self.trkpts[0].time, trkpt.time).into(),
+                trkpt.lat.into(),
+                trkpt.lon.into(),
+                trkpt.elevation_meters.into(),
+                trkpt.meters.into(),
+                trkpt.meters_per_second.into(),
+                trkpt.vertical_mps.into(),
+                trkpt.heart_rate.into(),
+                trkpt.cadence.into(),
+            ]);
+        }
+        sheet
     }
 
-    pub fn analyze(&self, duration: u8, rest: u8, count: u8, tod: bool) {
-        let intervals = self.intervals(duration, rest, count);
-        self.dump(&intervals, tod);
+    fn local_time(when: DateTime<Utc>) -> Cell {
+        when.with_timezone(crate::tz())
+            .naive_local()
+            .to_string()
+            .into()
+    }
 
-        if intervals.len() != usize::from(count) {
-            panic!(
-                "Was told to find {} intervals, but found {}",
-                count,
-                intervals.len()
-            );
+    // Converts cadences recorded in strides to steps, guessing which they
+    // are from the median cadence when unit is Auto.  Returns whether they
+    // were converted.
+    pub fn cadence_in_steps(&mut self, unit: CadenceUnit) -> bool {
+        let strides = match unit {
+            CadenceUnit::Strides => true,
+            CadenceUnit::Steps => false,
+            CadenceUnit::Auto => {
+                let mut cadences = self
+                    .trkpts
+                    .iter()
+                    .filter_map(|t| t.cadence)
+                    .filter(|&c| c > 0)
+                    .collect::<Vec<_>>();
+                cadences.sort_unstable();
+                cadences
+                    .get(cadences.len() / 2)
+                    .is_some_and(|&median| f64::from(median) < MIN_STEPS_PER_MINUTE)
+            }
+        };
+        if strides {
+            for trkpt in &mut self.trkpts {
+                trkpt.cadence = trkpt.cadence.map(|c| c.saturating_mul(2));
+            }
         }
+        strides
+    }
+
+    // Smooths the elevations (and vertical speeds), so neither the 3D
+    // distances fill_in_meters_per_second computes nor the intervals' gains
+    // and losses pick up their noise.
+    pub fn smooth_elevation(&mut self, filter: &ElevationFilter) {
+        elevation::smooth_trkpts(filter, &mut self.trkpts);
     }
 
     pub fn already_has_meters_per_second(&mut self) -> bool {
         self.trkpts.iter().all(|t| t.meters_per_second.is_some())
     }
 
-    pub fn fill_in_meters_per_second(&mut self) {
-        let mut iter = self.trkpts.iter_mut();
-        if let Some(&mut Trkpt {
-            mut lat,
-            mut lon,
-            mut time,
-            mut elevation_meters,
-            ..
-        }) = iter.next()
-        {
-            for trkpt in iter {
-                let new_lat = trkpt.lat;
-                let new_lon = trkpt.lon;
-                let new_time = trkpt.time;
-                let new_elevation_meters = trkpt.elevation_meters;
-                let duration =
-                    ((new_time - time).num_microseconds().unwrap() as f64) / 1_000_000.00;
-                let length_2d = Haversine.length(&LineString::<f64>::from(vec![
-                    (lon, lat),
-                    (new_lon, new_lat),
-                ]));
-                let length_3d = match (new_elevation_meters, elevation_meters) {
-                    (Some(em1), Some(em2)) => (length_2d.powi(2) + (em1 - em2).powi(2)).sqrt(),
-                    _ => length_2d,
-                };
+    // Computes speeds from positions, returning the implausible ones that
+    // were replaced.
+    pub fn fill_in_meters_per_second(&mut self, config: &SpeedConfig) -> Vec<Correction> {
+        speed::fill_in(config, &mut self.trkpts)
+    }
+}
 
-                let candidate = length_3d / duration;
-                trkpt.meters_per_second = Some(if candidate.is_nan() { 0.0 } else { candidate });
-                lat = new_lat;
-                lon = new_lon;
-                time = new_time;
-                elevation_meters = new_elevation_meters;
-            }
+impl From<Vec<Trkpt>> for Gpx {
+    fn from(trkpts: Vec<Trkpt>) -> Self {
+        Gpx {
+            trkpts,
+            segment_starts: Vec::new(),
         }
     }
 }
@@ -389,8 +1368,217 @@ impl FromStr for Gpx {
     type Err = roxmltree::Error;
 
     fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (trkpts, segment_starts) = Self::segments_from(&Document::parse(string)?);
         Ok(Gpx {
-            trkpts: Self::trkpts(&Document::parse(string)?),
+            trkpts,
+            segment_starts,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One sample per second at 2 m/s, except for count bursts of duration
+    // seconds at 4 m/s separated by rest seconds.
+    fn workout(duration: i64, rest: i64, count: i64) -> Gpx {
+        let warm_up = 300;
+        let length = warm_up + count * (duration + rest) + 300;
+        let trkpts = (0..length)
+            .map(|second| {
+                let into = second - warm_up;
+                let working = into >= 0
+                    && into < count * (duration + rest)
+                    && into % (duration + rest) < duration;
+                Trkpt::at(second).with_speed(if working { 4.0 } else { 2.0 })
+            })
+            .collect::<Vec<_>>();
+        Gpx::from(trkpts)
+    }
+
+    #[test]
+    fn test_interval_report() {
+        let gpx = workout(75, 30, 6);
+        let seconds = time::Duration::from_secs;
+        let config = IntervalDetectionConfig::default();
+        let report = gpx
+            .interval_report(seconds(75), seconds(30), 6, &config)
+            .unwrap();
+
+        assert_eq!(6, report.intervals.len());
+        assert!(report.intervals.iter().all(|i| i.rank() == 4.0));
+        assert!(report.average.is_some());
+
+        let Err(IntervalError::Count(e)) =
+            gpx.interval_report(seconds(75), seconds(30), 7, &config)
+        else {
+            panic!("found 7 intervals");
+        };
+        assert_eq!(7, e.expected);
+        assert_eq!(6, e.report.intervals.len());
+
+        for config in [
+            IntervalDetectionConfig {
+                span_slop: -1.0,
+                ..Default::default()
+            },
+            IntervalDetectionConfig {
+                min_rank_ratio: f64::NAN,
+                ..Default::default()
+            },
+            IntervalDetectionConfig {
+                rest_fraction: f64::INFINITY,
+                ..Default::default()
+            },
+        ] {
+            assert!(config.validate().is_err());
+            assert!(matches!(
+                gpx.interval_report(seconds(75), seconds(30), 6, &config),
+                Err(IntervalError::Config(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_distance_interval_report() {
+        // The 4 m/s bursts cover 300 m, so 200 m repetitions take 50 s.
+        let gpx = workout(75, 30, 6);
+        let rest = time::Duration::from_secs(55);
+        let config = IntervalDetectionConfig::default();
+        let report = gpx
+            .distance_interval_report(200.0, rest, 6, &config)
+            .unwrap();
+
+        assert_eq!(6, report.intervals.len());
+        for interval in &report.intervals {
+            assert!((interval.rank() - 4.0).abs() < 1e-9);
+            assert_eq!(50, (interval.stop() - interval.start()).num_seconds());
+        }
+
+        // Interpolating: 210 m at 4 m/s is 52.5 s.
+        let report = gpx
+            .distance_interval_report(210.0, rest, 6, &config)
+            .unwrap();
+        let elapsed = report.intervals[0].stop() - report.intervals[0].start();
+        assert_eq!(52_500, elapsed.num_milliseconds());
+    }
+
+    #[test]
+    fn test_rank_by_gap() {
+        // The second burst is run up a 10% grade at 3.5 m/s, which is slower
+        // than the first but harder.
+        let mut gpx = workout(60, 60, 2);
+        for trkpt in &mut gpx.trkpts[421..481] {
+            trkpt.meters_per_second = Some(3.5);
+            trkpt.vertical_mps = Some(0.35);
+        }
+        let seconds = time::Duration::from_secs;
+        let fastest = |rank_by| {
+            let config = IntervalDetectionConfig {
+                rank_by,
+                ..Default::default()
+            };
+            let report = gpx
+                .interval_report(seconds(60), seconds(60), 1, &config)
+                .unwrap();
+            report.intervals[0].clone()
+        };
+
+        let by_speed = fastest(RankBy::Speed);
+        assert!((by_speed.rank() - 4.0).abs() < 1e-9);
+        assert_eq!(by_speed.minutes_per_mile, by_speed.gap_minutes_per_mile);
+
+        let by_gap = fastest(RankBy::GradeAdjusted);
+        assert!(by_gap.start() > by_speed.start());
+        assert!((by_gap.rank() - 3.5 * gap::adjustment(3.5, 0.35)).abs() < 1e-9);
+        assert!(by_gap.gap_minutes_per_mile < by_gap.minutes_per_mile);
+    }
+
+    #[test]
+    fn test_cadence() {
+        let mut gpx = workout(75, 30, 6);
+        for trkpt in &mut gpx.trkpts {
+            trkpt.cadence = Some(90);
+        }
+        assert!(gpx.cadence_in_steps(CadenceUnit::Auto));
+        assert!(!gpx.cadence_in_steps(CadenceUnit::Auto));
+        assert_eq!(Some(180), gpx.trkpts[0].cadence);
+
+        let seconds = time::Duration::from_secs;
+        let report = gpx
+            .interval_report(seconds(75), seconds(30), 6, &Default::default())
+            .unwrap();
+        for interval in &report.intervals {
+            assert!((interval.cadence().unwrap() - 180.0).abs() < 1e-9);
+            // 4 m/s at 3 steps a second
+            assert!((interval.stride_length().unwrap() - 4.0 / 3.0).abs() < 1e-9);
+        }
+
+        let report = gpx
+            .distance_interval_report(210.0, seconds(55), 6, &Default::default())
+            .unwrap();
+        assert!((report.intervals[0].cadence().unwrap() - 180.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_explain() {
+        let gpx = workout(75, 30, 6);
+        let seconds = time::Duration::from_secs;
+        // Just the bursts and the (slow) windows on either side of them
+        let verdicts = |config: &IntervalDetectionConfig| {
+            gpx.explain(seconds(75), seconds(30), 4, config)
+                .unwrap()
+                .candidates[2..10]
+                .iter()
+                .map(|c| c.verdict)
+                .collect::<Vec<_>>()
+        };
+        let explanation = gpx
+            .explain(seconds(75), seconds(30), 4, &Default::default())
+            .unwrap();
+
+        // Three slow windows in the warm-up, the six bursts and four slow
+        // windows in the cool-down
+        assert_eq!(13, explanation.candidates.len());
+        assert_eq!(4, explanation.report().unwrap().intervals.len());
+        // Every other window overlaps (a rest's worth of) a faster one.
+        let windows = gpx.trkpts().len() - 75;
+        let precluded = explanation.candidates.iter().map(|c| c.precluded.len());
+        assert_eq!(
+            windows,
+            explanation.candidates.len() + precluded.sum::<usize>()
+        );
+        for c in &explanation.candidates {
+            assert!(!c.precluded.is_empty());
+            assert!(c.precluded.iter().all(|p| {
+                p.rank <= c.interval.rank()
+                    && p.start < c.interval.stop + TimeDelta::seconds(15)
+                    && p.stop > c.interval.start
+            }));
+        }
+        assert_eq!(Verdict::OutsideSet, explanation.candidates[0].verdict);
+
+        use Verdict::*;
+        let config = IntervalDetectionConfig {
+            trim: Trim::End,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                TooSlow, Accepted, Accepted, Accepted, Accepted, Trimmed, Trimmed, TooSlow
+            ],
+            verdicts(&config)
+        );
+        let config = IntervalDetectionConfig {
+            trim: Trim::Start,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                TooSlow, Trimmed, Trimmed, Accepted, Accepted, Accepted, Accepted, TooSlow
+            ],
+            verdicts(&config)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+// Heart rate during and after each interval, to show fatigue that pace
+// alone doesn't: how hard each interval was (average and max), how long the
+// heart rate took to peak, how far it fell during the rest that followed
+// and how much it drifted up across the set.
+//
+// A heart rate is a reading rather than something that covers the time
+// since the previous trkpt, so an interval's heart rates are the readings
+// from its start through its stop and the heart rate at any other moment is
+// the latest reading at or before it.
+
+use {
+    crate::gpx::{Gpx, IntervalReport, Trkpt, seconds_between},
+    chrono::{DateTime, TimeDelta, Utc},
+    digital_duration_nom::duration::Duration,
+    std::fmt::{self, Display, Formatter},
+};
+
+// How long after an interval's stop the recovery is measured
+const RECOVERY_SECONDS: [i64; 2] = [30, 60];
+
+#[derive(Clone, Debug)]
+pub struct IntervalHeartRate {
+    pub start: DateTime<Utc>,
+    pub average: f64,
+    pub max: u8,
+    // Seconds from the start to the first reading at max
+    pub time_to_peak: f64,
+    // The drop from the stop to RECOVERY_SECONDS later, in bpm.  None when
+    // the next interval started before then or the activity ended.
+    pub recovery: [Option<i16>; 2],
+}
+
+#[derive(Debug)]
+pub struct HeartRateReport {
+    origin: Option<DateTime<Utc>>,
+    pub intervals: Vec<IntervalHeartRate>,
+}
+
+// The latest reading at or before when
+fn heart_rate_at(trkpts: &[Trkpt], when: DateTime<Utc>) -> Option<u8> {
+    let after = trkpts.partition_point(|t| t.time <= when);
+    trkpts[..after].iter().rev().find_map(|t| t.heart_rate)
+}
+
+impl HeartRateReport {
+    // None when the activity has no heart rates.
+    pub fn new(gpx: &Gpx, report: &IntervalReport) -> Option<Self> {
+        let trkpts = gpx.trkpts();
+        if trkpts.iter().all(|t| t.heart_rate.is_none()) {
+            return None;
+        }
+        let end = trkpts.last()?.time;
+        let intervals = report
+            .intervals
+            .iter()
+            .enumerate()
+            .filter_map(|(i, interval)| {
+                let first = trkpts.partition_point(|t| t.time < interval.start());
+                let readings = trkpts[first..]
+                    .iter()
+                    .take_while(|t| t.time <= interval.stop())
+                    .filter_map(|t| t.heart_rate.map(|hr| (t.time, hr)))
+                    .collect::<Vec<_>>();
+                let max = readings.iter().map(|&(_, hr)| hr).max()?;
+                let peak = readings.iter().find(|&&(_, hr)| hr == max)?.0;
+                let sum = readings.iter().map(|&(_, hr)| f64::from(hr)).sum::<f64>();
+
+                let rest_until = report.intervals.get(i + 1).map_or(end, |next| next.start());
+                let at_stop = heart_rate_at(trkpts, interval.stop());
+                let recovery = RECOVERY_SECONDS.map(|seconds| {
+                    let when = interval.stop() + TimeDelta::seconds(seconds);
+                    if when > rest_until {
+                        return None;
+                    }
+                    Some(i16::from(at_stop?) - i16::from(heart_rate_at(trkpts, when)?))
+                });
+
+                Some(IntervalHeartRate {
+                    start: interval.start(),
+                    average: sum / readings.len() as f64,
+                    max,
+                    time_to_peak: seconds_between(interval.start(), peak),
+                    recovery,
+                })
+            })
+            .collect();
+
+        Some(HeartRateReport {
+            origin: trkpts.first().map(|t| t.time),
+            intervals,
+        })
+    }
+
+    // How much higher the last interval's average was than the first's.
+    pub fn drift(&self) -> Option<f64> {
+        let first = self.intervals.first()?;
+        let last = self.intervals.last()?;
+        Some(last.average - first.average)
+    }
+}
+
+impl Display for HeartRateReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let optional = |bpm: Option<i16>| bpm.map_or_else(|| "-".to_string(), |b| b.to_string());
+        writeln!(
+            f,
+            "{:>9} {:>5} {:>4} {:>7} {:>5} {:>5}",
+            "Start", "HR", "Max", "Peak at", "HRR30", "HRR60"
+        )?;
+        for i in &self.intervals {
+            let origin = self.origin.unwrap_or(i.start);
+            writeln!(
+                f,
+                "{:9.1} {:5.1} {:4} {:7.1} {:>5} {:>5}",
+                Duration::from(seconds_between(origin, i.start)),
+                i.average,
+                i.max,
+                Duration::from(i.time_to_peak),
+                optional(i.recovery[0]),
+                optional(i.recovery[1])
+            )?;
+        }
+        if let Some(drift) = self.drift() {
+            writeln!(f, "Drift: {drift:+.1} bpm")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpx::IntervalDetectionConfig;
+    use std::time;
+
+    #[test]
+    fn test_heart_rate_report() {
+        // Two 60 s intervals at 4 m/s with 90 s of jogging between them.
+        // The heart rate climbs by 1 bpm a second to 150 (the first time) or
+        // 160 (the second) and falls by 1 bpm a second once it's over.
+        let heart_rate = |second: i64| match second {
+            ..299 => 100,
+            299..=359 => 100 + (second - 299).min(50),
+            360..449 => 150 - (second - 359),
+            449..=509 => 100 + (second - 449),
+            _ => 160 - (second - 509),
+        };
+        let trkpts = (0..560)
+            .map(|second| {
+                let fast = (300..360).contains(&second) || (450..510).contains(&second);
+                Trkpt::at(second)
+                    .with_speed(if fast { 4.0 } else { 2.0 })
+                    .with_hr(heart_rate(second) as u8)
+            })
+            .collect::<Vec<_>>();
+        let gpx = Gpx::from(trkpts);
+        let seconds = time::Duration::from_secs;
+        let report = gpx
+            .interval_report(
+                seconds(60),
+                seconds(90),
+                2,
+                &IntervalDetectionConfig::default(),
+            )
+            .unwrap();
+        let heart_rates = HeartRateReport::new(&gpx, &report).unwrap();
+
+        assert_eq!(2, heart_rates.intervals.len());
+        let (first, second) = (&heart_rates.intervals[0], &heart_rates.intervals[1]);
+        assert_eq!(150, first.max);
+        assert_eq!(50.0, first.time_to_peak);
+        assert_eq!(160, second.max);
+        assert_eq!(60.0, second.time_to_peak);
+        assert_eq!([Some(30), Some(60)], first.recovery);
+        assert_eq!([Some(30), None], second.recovery);
+        assert!((first.average - 7875.0 / 61.0).abs() < 1e-9);
+        assert!((second.average - 130.0).abs() < 1e-9);
+        assert!((heart_rates.drift().unwrap() - (130.0 - 7875.0 / 61.0)).abs() < 1e-9);
+    }
+}
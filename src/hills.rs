@@ -0,0 +1,285 @@
+// Hill repeats: climbs found from elevation rather than speed.
+//
+// The elevation comes from integrating vertical speed when the activity has
+// it (as the interval ranking does) and from the recorded elevations
+// otherwise.  The profile is reduced to alternating bottoms and tops, where
+// a turn only counts once the elevation has come back reversal_meters from
+// the extreme, so noise doesn't split a climb.  Every bottom-to-top rise of
+// at least min_gain_meters is a repeat and the time until the next climb
+// starts (usually jogging back down) is its recovery.
+
+use {
+    crate::gpx::{Gpx, IntervalReport, Trkpt, seconds_between},
+    chrono::{DateTime, Utc},
+    digital_duration_nom::duration::Duration,
+    std::fmt::{self, Display, Formatter},
+};
+
+const SECONDS_PER_HOUR: f64 = 3600.0;
+
+#[derive(Clone, Debug)]
+pub struct HillConfig {
+    pub min_gain_meters: f64,
+    pub reversal_meters: f64,
+}
+
+impl Default for HillConfig {
+    fn default() -> Self {
+        HillConfig {
+            min_gain_meters: 10.0,
+            reversal_meters: 3.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Point {
+    time: DateTime<Utc>,
+    elevation: f64,
+    meters: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct HillRepeat {
+    pub start: DateTime<Utc>,
+    pub top: DateTime<Utc>,
+    pub gain: f64,
+    // Horizontal meters covered on the way up
+    pub meters: f64,
+    // Where the next climb starts (or the activity's low point after the
+    // last climb)
+    pub bottom: Option<DateTime<Utc>>,
+    pub descent: f64,
+}
+
+#[derive(Debug)]
+pub struct HillReport {
+    origin: Option<DateTime<Utc>>,
+    pub repeats: Vec<HillRepeat>,
+}
+
+impl HillRepeat {
+    pub fn seconds(&self) -> f64 {
+        seconds_between(self.start, self.top)
+    }
+
+    // Vertical meters per hour
+    pub fn vam(&self) -> f64 {
+        self.gain / self.seconds() * SECONDS_PER_HOUR
+    }
+
+    // Rise over run; None when there's no horizontal distance to go by.
+    pub fn grade(&self) -> Option<f64> {
+        (self.meters > 0.0).then(|| self.gain / self.meters)
+    }
+
+    // Seconds from the top to the next bottom
+    pub fn descent_recovery(&self) -> Option<f64> {
+        self.bottom.map(|bottom| seconds_between(self.top, bottom))
+    }
+}
+
+// Elevation and horizontal distance at every trkpt that has them.
+fn points(trkpts: &[Trkpt]) -> Vec<Point> {
+    let use_vertical_speed = trkpts.iter().any(|t| t.vertical_mps.is_some());
+    let mut points = Vec::with_capacity(trkpts.len());
+    let Some(first) = trkpts.first() else {
+        return points;
+    };
+    let mut last = Point {
+        time: first.time,
+        elevation: if use_vertical_speed {
+            0.0
+        } else {
+            first.elevation_meters.unwrap_or(0.0)
+        },
+        meters: 0.0,
+    };
+
+    points.push(last);
+    for trkpt in &trkpts[1..] {
+        let seconds = seconds_between(last.time, trkpt.time);
+        let elevation = if use_vertical_speed {
+            trkpt.vertical_mps.map(|v| last.elevation + v * seconds)
+        } else {
+            trkpt.elevation_meters
+        };
+        let Some(elevation) = elevation else {
+            continue;
+        };
+        last = Point {
+            time: trkpt.time,
+            elevation,
+            meters: last.meters + trkpt.meters_per_second.unwrap_or(0.0) * seconds,
+        };
+        points.push(last);
+    }
+    points
+}
+
+// Indexes of alternating bottoms and tops, starting with a bottom.
+fn turns(points: &[Point], reversal_meters: f64) -> Vec<usize> {
+    let mut turns = Vec::new();
+    let (mut low, mut high) = (0, 0);
+    let mut climbing = None;
+
+    // A climb starts at the last point at its bottom, but ends at the first
+    // point at its top.
+    for (i, point) in points.iter().enumerate() {
+        if point.elevation <= points[low].elevation {
+            low = i;
+        }
+        if point.elevation > points[high].elevation {
+            high = i;
+        }
+        if climbing != Some(true) && point.elevation - points[low].elevation >= reversal_meters {
+            turns.push(low);
+            climbing = Some(true);
+            high = i;
+        } else if climbing == Some(true)
+            && points[high].elevation - point.elevation >= reversal_meters
+        {
+            turns.push(high);
+            climbing = Some(false);
+            low = i;
+        }
+    }
+    // A climb that's still going (or a descent that didn't turn) ends at
+    // its extreme.
+    match climbing {
+        Some(true) => turns.push(high),
+        Some(false) => turns.push(low),
+        None => (),
+    }
+    turns
+}
+
+pub fn hill_repeats(gpx: &Gpx, config: &HillConfig) -> HillReport {
+    let trkpts = gpx.trkpts();
+    let points = points(trkpts);
+    let turns = turns(&points, config.reversal_meters);
+    let repeats = turns
+        .windows(2)
+        .step_by(2)
+        .enumerate()
+        .filter_map(|(i, pair)| {
+            let (bottom, top) = (&points[pair[0]], &points[pair[1]]);
+            let gain = top.elevation - bottom.elevation;
+            if gain < config.min_gain_meters {
+                return None;
+            }
+            let next = turns.get(2 * i + 2).map(|&next| &points[next]);
+            Some(HillRepeat {
+                start: bottom.time,
+                top: top.time,
+                gain,
+                meters: top.meters - bottom.meters,
+                bottom: next.map(|n| n.time),
+                descent: next.map_or(0.0, |n| top.elevation - n.elevation),
+            })
+        })
+        .collect();
+
+    HillReport {
+        origin: trkpts.first().map(|t| t.time),
+        repeats,
+    }
+}
+
+impl HillReport {
+    // The climbs as intervals, so they can be dumped and exported like any
+    // other intervals.
+    pub fn interval_report(&self, gpx: &Gpx) -> IntervalReport {
+        IntervalReport::new(
+            self.repeats
+                .iter()
+                .filter_map(|r| gpx.interval_between(r.start, r.top))
+                .collect(),
+        )
+    }
+}
+
+impl Display for HillReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let elapsed = |when| {
+            let origin = self.origin.unwrap_or(when);
+            Duration::from(seconds_between(origin, when))
+        };
+        writeln!(
+            f,
+            "{:>9} {:>7} {:>6} {:>5} {:>6} {:>8} {:>7}",
+            "Start", "Climb", "Gain", "Grade", "VAM", "Recovery", "Descent"
+        )?;
+        for r in &self.repeats {
+            let grade = r
+                .grade()
+                .map_or_else(|| "-".to_string(), |g| format!("{:.1}%", g * 100.0));
+            let recovery = r
+                .descent_recovery()
+                .map_or_else(|| "-".to_string(), |s| format!("{:.1}", Duration::from(s)));
+            writeln!(
+                f,
+                "{:9.1} {:7.1} {:6.1} {grade:>5} {:6.0} {recovery:>8} {:7.1}",
+                elapsed(r.start),
+                Duration::from(r.seconds()),
+                r.gain,
+                r.vam(),
+                r.descent
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One sample per second running at 3 m/s with the given vertical speeds
+    // for the given numbers of seconds.
+    fn profile(segments: &[(f64, i64)]) -> Gpx {
+        let vertical = segments
+            .iter()
+            .flat_map(|&(vertical_mps, seconds)| (0..seconds).map(move |_| vertical_mps));
+        let trkpts = std::iter::once(0.0)
+            .chain(vertical)
+            .enumerate()
+            .map(|(second, vertical_mps)| {
+                Trkpt::at(second as i64)
+                    .with_speed(3.0)
+                    .with_vertical(vertical_mps)
+            })
+            .collect::<Vec<_>>();
+        Gpx::from(trkpts)
+    }
+
+    #[test]
+    fn test_hill_repeats() {
+        // Two 60 s climbs of 30 m, each followed by a 60 s descent, with a
+        // 68 s flat with a 2 m bump (which isn't a climb) between them.
+        let gpx = profile(&[
+            (0.0, 60),
+            (0.5, 60),
+            (-0.5, 60),
+            (0.0, 30),
+            (0.5, 4),
+            (-0.5, 4),
+            (0.0, 30),
+            (0.5, 60),
+            (-0.5, 60),
+        ]);
+        let report = hill_repeats(&gpx, &HillConfig::default());
+
+        assert_eq!(2, report.repeats.len());
+        for repeat in &report.repeats {
+            assert_eq!(60.0, repeat.seconds());
+            assert!((repeat.gain - 30.0).abs() < 1e-9);
+            assert!((repeat.vam() - 1800.0).abs() < 1e-6);
+            assert!((repeat.grade().unwrap() - 30.0 / 180.0).abs() < 1e-9);
+            assert!((repeat.descent - 30.0).abs() < 1e-9);
+        }
+        assert_eq!(Some(128.0), report.repeats[0].descent_recovery());
+        assert_eq!(Some(60.0), report.repeats[1].descent_recovery());
+        assert_eq!(2, report.interval_report(&gpx).intervals.len());
+    }
+}
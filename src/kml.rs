@@ -1 +1,189 @@
-pub struct Kml {}
+// Reader for the KML that Movescount exported.  The samples are in a
+// gx:Track: a <when> and a <gx:coord> per sample followed by ExtendedData
+// arrays that have one value per sample.
+
+use {
+    crate::gpx::{Gpx, Trkpt},
+    chrono::{DateTime, NaiveDateTime, TimeZone, Utc},
+    roxmltree::{Document, Node},
+    std::{
+        error,
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+    },
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Xml(roxmltree::Error),
+    Format(String),
+}
+
+impl error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Xml(e) => write!(f, "{e}"),
+            Error::Format(message) => write!(f, "Bad KML track: {message}"),
+        }
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(e: roxmltree::Error) -> Self {
+        Error::Xml(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct Kml {
+    trkpts: Vec<Trkpt>,
+}
+
+impl Kml {
+    pub fn trkpts(&self) -> &[Trkpt] {
+        &self.trkpts
+    }
+
+    // Movescount writes local times without an offset, but other KML
+    // writers include one.
+    fn time(text: &str) -> Option<DateTime<Utc>> {
+        DateTime::<Utc>::from_str(text).ok().or_else(|| {
+            let naive = NaiveDateTime::from_str(text).ok()?;
+            crate::tz()
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc))
+        })
+    }
+
+    fn array(track: &Node, name: &str) -> Vec<Option<f64>> {
+        track
+            .descendants()
+            .find(|n| n.has_tag_name("SimpleArrayData") && n.attribute("name") == Some(name))
+            .map(|array| {
+                array
+                    .children()
+                    .filter(|n| n.has_tag_name("value"))
+                    .map(|value| value.text().and_then(|t| f64::from_str(t.trim()).ok()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn trkpts_from(track: &Node) -> Result<Vec<Trkpt>, Error> {
+        let whens = track
+            .children()
+            .filter(|n| n.has_tag_name("when"))
+            .map(|when| {
+                let text = when.text().unwrap_or_default();
+                Self::time(text).ok_or_else(|| Error::Format(format!("bad time {text}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let coords = track
+            .children()
+            .filter(|n| n.has_tag_name("coord"))
+            .map(|coord| {
+                coord
+                    .text()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(|number| f64::from_str(number).ok())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        if whens.len() != coords.len() {
+            return Err(Error::Format(format!(
+                "{} times but {} coordinates",
+                whens.len(),
+                coords.len()
+            )));
+        }
+
+        let heart_rate = Self::array(track, "Heartrate");
+        let altitude = Self::array(track, "Altitude");
+        let cadence = Self::array(track, "Cadence");
+        let distance = Self::array(track, "Distance");
+        let speed = Self::array(track, "Speed");
+        let vertical_speed = Self::array(track, "VerticalSpeed");
+        let at = |array: &[Option<f64>], i: usize| array.get(i).copied().flatten();
+        let u8_at = |array: &[Option<f64>], i| {
+            at(array, i).map(|value| value.round().clamp(0.0, 255.0) as u8)
+        };
+
+        Ok(whens
+            .into_iter()
+            .zip(coords)
+            .enumerate()
+            .map(|(i, (time, coord))| {
+                let coord_at = |j: usize| coord.get(j).copied().flatten();
+                Trkpt {
+                    time,
+                    meters_per_second: at(&speed, i),
+                    meters: at(&distance, i),
+                    heart_rate: u8_at(&heart_rate, i),
+                    cadence: u8_at(&cadence, i),
+                    elevation_meters: at(&altitude, i).or_else(|| coord_at(2)),
+                    vertical_mps: at(&vertical_speed, i),
+                    lat: coord_at(1),
+                    lon: coord_at(0),
+                }
+            })
+            .collect())
+    }
+}
+
+impl FromStr for Kml {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let doc = Document::parse(string)?;
+        let Some(track) = doc.descendants().find(|n| n.has_tag_name("Track")) else {
+            return Err(Error::Format("no gx:Track".to_string()));
+        };
+
+        Ok(Kml {
+            trkpts: Self::trkpts_from(&track)?,
+        })
+    }
+}
+
+impl From<Kml> for Gpx {
+    fn from(kml: Kml) -> Self {
+        Gpx::from(kml.trkpts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let kml = std::fs::read_to_string("assets/Move_2018_12_17_06_59_29_Running.kml")
+            .unwrap()
+            .parse::<Kml>()
+            .unwrap();
+        let trkpts = kml.trkpts();
+
+        assert_eq!(7615, trkpts.len());
+        // 06:59:29 in Denver
+        assert_eq!(
+            DateTime::<Utc>::from_timestamp(1_545_055_169, 0).unwrap(),
+            trkpts[0].time
+        );
+        assert_eq!(Some(35.167814), trkpts[0].lat);
+        assert_eq!(Some(-106.545514), trkpts[0].lon);
+        assert_eq!(Some(88), trkpts[0].heart_rate);
+        assert!(trkpts.windows(2).all(|w| w[0].time <= w[1].time));
+
+        assert!(matches!(
+            "<kml><gx:Track xmlns:gx=\"gx\"><when>2018-12-17T06:59:29</when></gx:Track></kml>"
+                .parse::<Kml>(),
+            Err(Error::Format(_))
+        ));
+        assert!(matches!("<kml>".parse::<Kml>(), Err(Error::Xml(_))));
+    }
+}
@@ -3,13 +3,31 @@ use {
     std::sync::OnceLock,
 };
 
+pub mod best;
+pub mod compare;
+pub mod critical_speed;
+pub mod detect;
+pub mod elevation;
+pub mod export;
 pub mod fit;
+pub mod gap;
 pub mod gpx;
+pub mod heart_rate;
+pub mod hills;
 pub mod interval_parse;
 pub mod kml;
 pub mod misc;
+pub mod pause;
+pub mod power;
+pub mod predict;
+pub mod records;
+pub mod speed;
+pub mod splits;
 pub mod tcx;
+pub mod training_load;
+pub mod workout;
 pub mod xlsx;
+pub mod zones;
 
 static TZ: OnceLock<Tz> = OnceLock::new();
 
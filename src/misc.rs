@@ -1,11 +1,18 @@
 // random code that doesn't have a better place to live.
 
-use std::{
-    fs,
-    io::{Read, Result},
-    path::Path,
+use {
+    crate::{fit::Fit, gpx::Gpx, kml::Kml, speed::SpeedConfig, xlsx::Xlsx},
+    std::{
+        ffi::OsStr,
+        fs,
+        io::{self, Read, Result},
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
 };
 
+const ACTIVITY_EXTENSIONS: [&str; 4] = ["gpx", "fit", "kml", "xlsx"];
+
 pub fn contents_from(path: &Path) -> Result<String> {
     let mut contents = String::new();
     let mut file = fs::File::open(path)?;
@@ -13,3 +20,54 @@ pub fn contents_from(path: &Path) -> Result<String> {
 
     Ok(contents)
 }
+
+// Reads any activity format we understand, choosing the parser by
+// extension.  The samples are left as they are in the file; in particular
+// GPX speeds are not filled in.
+pub fn activity_from(path: &Path) -> Result<Gpx> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("gpx") => Gpx::from_str(&contents_from(path)?).map_err(io::Error::other),
+        Some("kml") => Ok(Kml::from_str(&contents_from(path)?)
+            .map_err(io::Error::other)?
+            .into()),
+        Some("fit") => Ok(Fit::from_bytes(&fs::read(path)?)
+            .map_err(io::Error::other)?
+            .into()),
+        Some("xlsx") => Ok(Xlsx::from_reader(fs::File::open(path)?)
+            .map_err(io::Error::other)?
+            .into()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown activity format: {}", path.display()),
+        )),
+    }
+}
+
+// Like activity_from, but with GPX speeds filled in (with the default
+// speed config), so any activity is ready to be analyzed.
+pub fn activity_with_speeds_from(path: &Path) -> Result<Gpx> {
+    let mut gpx = activity_from(path)?;
+    if path.extension() == Some(OsStr::new("gpx")) {
+        gpx.fill_in_meters_per_second(&SpeedConfig::default());
+    }
+    Ok(gpx)
+}
+
+// Every file under directory (searched recursively) that activity_from
+// understands
+pub fn activity_paths(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            paths.extend(activity_paths(&path)?);
+        } else if path
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|e| ACTIVITY_EXTENSIONS.contains(&e))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
@@ -0,0 +1,285 @@
+// Stopped time, so an activity (or an interval) that includes waiting at a
+// light doesn't look slower than it was run.
+//
+// The time each trkpt covers (since the previous one) is stopped when its
+// speed is below min_meters_per_second, when it's more than max_gap_seconds
+// (the watch paused or lost the signal) or when the trkpt starts a new GPX
+// trkseg.  The rest is moving time and moving pace is the distance covered
+// while moving over the moving time.
+
+use {
+    crate::gpx::{Gpx, IntervalReport, METERS_PER_MILE, seconds_between},
+    chrono::{DateTime, Utc},
+    digital_duration_nom::duration::Duration,
+    std::fmt::{self, Display, Formatter},
+};
+
+#[derive(Clone, Debug)]
+pub struct PauseConfig {
+    pub min_meters_per_second: f64,
+    pub max_gap_seconds: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PauseReason {
+    Slow,
+    Gap,
+    Segment,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pause {
+    pub start: DateTime<Utc>,
+    pub stop: DateTime<Utc>,
+    // Why the pause started
+    pub reason: PauseReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Moving {
+    pub elapsed_seconds: f64,
+    pub moving_seconds: f64,
+    // Covered while moving
+    pub meters: f64,
+}
+
+#[derive(Debug)]
+pub struct MovingReport {
+    origin: Option<DateTime<Utc>>,
+    pub activity: Moving,
+    pub pauses: Vec<Pause>,
+    // Each interval's start and its moving time
+    pub intervals: Vec<(DateTime<Utc>, Moving)>,
+}
+
+impl Default for PauseConfig {
+    fn default() -> Self {
+        PauseConfig {
+            // Slower than anyone walks, but faster than GPS drift
+            min_meters_per_second: 0.5,
+            max_gap_seconds: 10.0,
+        }
+    }
+}
+
+impl Display for PauseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let text = match self {
+            PauseReason::Slow => "stopped",
+            PauseReason::Gap => "no samples",
+            PauseReason::Segment => "new segment",
+        };
+        f.write_str(text)
+    }
+}
+
+impl Moving {
+    pub fn stopped_seconds(&self) -> f64 {
+        self.elapsed_seconds - self.moving_seconds
+    }
+
+    // Per mile; None when there was no moving
+    pub fn moving_pace(&self) -> Option<Duration> {
+        (self.meters > 0.0)
+            .then(|| Duration::from(self.moving_seconds / self.meters * METERS_PER_MILE))
+    }
+}
+
+// Why the time up to gpx.trkpts()[i] (from the previous trkpt) was stopped,
+// if it was.
+fn pause_reason(gpx: &Gpx, config: &PauseConfig, i: usize) -> Option<PauseReason> {
+    let trkpts = gpx.trkpts();
+    if gpx.segment_starts().binary_search(&i).is_ok() {
+        Some(PauseReason::Segment)
+    } else if seconds_between(trkpts[i - 1].time, trkpts[i].time) > config.max_gap_seconds {
+        Some(PauseReason::Gap)
+    } else if trkpts[i]
+        .meters_per_second
+        .is_some_and(|speed| speed < config.min_meters_per_second)
+    {
+        Some(PauseReason::Slow)
+    } else {
+        None
+    }
+}
+
+pub fn pauses(gpx: &Gpx, config: &PauseConfig) -> Vec<Pause> {
+    let trkpts = gpx.trkpts();
+    let mut pauses = Vec::<Pause>::new();
+
+    for i in 1..trkpts.len() {
+        let Some(reason) = pause_reason(gpx, config, i) else {
+            continue;
+        };
+        match pauses.last_mut() {
+            Some(last) if last.stop == trkpts[i - 1].time => last.stop = trkpts[i].time,
+            _ => pauses.push(Pause {
+                start: trkpts[i - 1].time,
+                stop: trkpts[i].time,
+                reason,
+            }),
+        }
+    }
+    pauses
+}
+
+// Moving time and distance from start to stop
+pub fn moving_between(
+    gpx: &Gpx,
+    config: &PauseConfig,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> Moving {
+    let trkpts = gpx.trkpts();
+    let mut moving = Moving {
+        elapsed_seconds: seconds_between(start, stop),
+        moving_seconds: 0.0,
+        meters: 0.0,
+    };
+    let first = trkpts.partition_point(|t| t.time <= start).max(1);
+
+    for i in first..trkpts.len() {
+        if trkpts[i].time > stop {
+            break;
+        }
+        if pause_reason(gpx, config, i).is_none() {
+            let seconds = seconds_between(trkpts[i - 1].time.max(start), trkpts[i].time);
+            moving.moving_seconds += seconds;
+            moving.meters += trkpts[i].meters_per_second.unwrap_or(0.0) * seconds;
+        }
+    }
+    moving
+}
+
+impl MovingReport {
+    pub fn new(gpx: &Gpx, report: &IntervalReport, config: &PauseConfig) -> Self {
+        let trkpts = gpx.trkpts();
+        let activity = match (trkpts.first(), trkpts.last()) {
+            (Some(first), Some(last)) => moving_between(gpx, config, first.time, last.time),
+            _ => Moving {
+                elapsed_seconds: 0.0,
+                moving_seconds: 0.0,
+                meters: 0.0,
+            },
+        };
+        let intervals = report
+            .intervals
+            .iter()
+            .map(|i| (i.start(), moving_between(gpx, config, i.start(), i.stop())))
+            .collect();
+
+        MovingReport {
+            origin: trkpts.first().map(|t| t.time),
+            activity,
+            pauses: pauses(gpx, config),
+            intervals,
+        }
+    }
+}
+
+impl Display for MovingReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let elapsed = |when| Duration::from(seconds_between(self.origin.unwrap_or(when), when));
+        let pace = |moving: &Moving| {
+            moving
+                .moving_pace()
+                .map_or_else(|| "-".to_string(), |pace| format!("{pace:.1}"))
+        };
+        let a = &self.activity;
+        writeln!(
+            f,
+            "Moving {:.1}, stopped {:.1}, moving pace {}",
+            Duration::from(a.moving_seconds),
+            Duration::from(a.stopped_seconds()),
+            pace(a)
+        )?;
+        for pause in &self.pauses {
+            writeln!(
+                f,
+                "Paused {:9.1} {:9.1} ({})",
+                elapsed(pause.start),
+                elapsed(pause.stop),
+                pause.reason
+            )?;
+        }
+        if self.intervals.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "{:>9} {:>7} {:>7} {:>7}",
+            "Start", "Moving", "Stopped", "Pace"
+        )?;
+        for (start, moving) in &self.intervals {
+            writeln!(
+                f,
+                "{:9.1} {:7.1} {:7.1} {:>7}",
+                elapsed(*start),
+                Duration::from(moving.moving_seconds),
+                Duration::from(moving.stopped_seconds()),
+                pace(moving)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn trkpt(second: i64, meters_per_second: f64) -> String {
+        let time = DateTime::from_timestamp(1_545_055_169 + second, 0).unwrap();
+        // Debug is RFC 3339
+        format!(
+            r#"<trkpt lat="35.0" lon="-106.5"><time>{time:?}</time><speed>{meters_per_second}</speed></trkpt>"#
+        )
+    }
+
+    #[test]
+    fn test_moving() {
+        // 100 s at 3 m/s, 20 s standing, 80 s at 3 m/s, a 30 s gap, 10 s at
+        // 3 m/s and then, in a new trkseg a minute later, 10 s at 3 m/s.
+        let first = (1..=100)
+            .map(|s| trkpt(s, 3.0))
+            .chain((101..=120).map(|s| trkpt(s, 0.0)))
+            .chain((121..=200).map(|s| trkpt(s, 3.0)))
+            .chain((230..=240).map(|s| trkpt(s, 3.0)))
+            .collect::<String>();
+        let second = (300..=310).map(|s| trkpt(s, 3.0)).collect::<String>();
+        let gpx = Gpx::from_str(&format!(
+            "<gpx><trk><trkseg>{first}</trkseg><trkseg>{second}</trkseg></trk></gpx>"
+        ))
+        .unwrap();
+        let config = PauseConfig::default();
+
+        let reasons = pauses(&gpx, &config)
+            .iter()
+            .map(|p| (seconds_between(gpx.trkpts()[0].time, p.start), p.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (99.0, PauseReason::Slow),
+                (199.0, PauseReason::Gap),
+                (239.0, PauseReason::Segment)
+            ],
+            reasons
+        );
+
+        let report = MovingReport::new(&gpx, &IntervalReport::new(Vec::new()), &config);
+        let activity = report.activity;
+        assert_eq!(309.0, activity.elapsed_seconds);
+        assert_eq!(99.0 + 80.0 + 10.0 + 10.0, activity.moving_seconds);
+        assert_eq!(3.0 * activity.moving_seconds, activity.meters);
+        assert_eq!(
+            Duration::from(METERS_PER_MILE / 3.0).as_secs(),
+            activity.moving_pace().unwrap().as_secs()
+        );
+
+        let start = gpx.trkpts()[90].time;
+        let moving = moving_between(&gpx, &config, start, start + chrono::TimeDelta::seconds(60));
+        assert_eq!(40.0, moving.moving_seconds);
+        assert_eq!(20.0, moving.stopped_seconds());
+    }
+}
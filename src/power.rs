@@ -0,0 +1,307 @@
+// Running power estimated from speed, grade and the runner's mass, for
+// activities recorded without a power meter.
+//
+// Running on the flat takes about ECOR joules per kilogram per meter (the
+// energy cost of running that power meters report).  Grades scale that by
+// the same Minetti cost curve that grade-adjusted pace uses (see gap.rs).
+// Optionally, pushing through the air adds ½·ρ·CdA·(v + headwind)²·v.
+//
+// The grade is the vertical speed over the speed.  Trkpts without a
+// recorded vertical speed (e.g., GPS-only GPX) get one from their
+// elevations, smoothed first since a meter of noise over a second's running
+// is a 30% grade.
+//
+// Normalized power is the fourth root of the mean fourth power of the
+// 30-second rolling average, so that surges count for more than steady
+// running at the same average.
+
+use {
+    crate::{
+        elevation::{self, ElevationFilter},
+        gap,
+        gpx::{Gpx, IntervalReport, Trkpt, seconds_between},
+        training_load::MAX_GAP_SECONDS,
+    },
+    chrono::{DateTime, Utc},
+    digital_duration_nom::duration::Duration,
+    std::fmt::{self, Display, Formatter},
+};
+
+// J/kg/m
+const ECOR: f64 = 1.04;
+const ROLLING_SECONDS: usize = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Air {
+    // m²
+    pub drag_area: f64,
+    // kg/m³
+    pub density: f64,
+    // Negative for a tailwind
+    pub headwind_mps: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct PowerConfig {
+    pub mass_kg: f64,
+    // For elevations that become vertical speeds
+    pub filter: ElevationFilter,
+    pub air: Option<Air>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Power {
+    pub average: f64,
+    pub normalized: f64,
+}
+
+#[derive(Debug)]
+pub struct PowerReport {
+    origin: Option<DateTime<Utc>>,
+    pub activity: Option<Power>,
+    // Each interval's start and its power
+    pub intervals: Vec<(DateTime<Utc>, Option<Power>)>,
+}
+
+impl Default for Air {
+    fn default() -> Self {
+        Air {
+            drag_area: 0.24,
+            density: 1.225,
+            headwind_mps: 0.0,
+        }
+    }
+}
+
+impl PowerConfig {
+    pub fn new(mass_kg: f64) -> Self {
+        PowerConfig {
+            mass_kg,
+            filter: ElevationFilter::KALMAN,
+            air: None,
+        }
+    }
+
+    // Watts at meters_per_second and vertical_mps
+    pub fn watts(&self, meters_per_second: f64, vertical_mps: f64) -> f64 {
+        if meters_per_second <= 0.0 {
+            return 0.0;
+        }
+        let running = self.mass_kg
+            * meters_per_second
+            * ECOR
+            * gap::adjustment(meters_per_second, vertical_mps);
+        let air = self.air.map_or(0.0, |air| {
+            let relative = meters_per_second + air.headwind_mps;
+            0.5 * air.density * air.drag_area * relative * relative.abs() * meters_per_second
+        });
+        (running + air).max(0.0)
+    }
+}
+
+// Each trkpt's recorded vertical speed or, failing that, the one from its
+// smoothed elevation and the previous one's.
+fn vertical_speeds(trkpts: &[Trkpt], filter: &ElevationFilter) -> Vec<Option<f64>> {
+    let mut smoothed = elevation::smooth(filter, &elevation::samples(trkpts)).into_iter();
+    let mut previous: Option<(DateTime<Utc>, f64)> = None;
+
+    trkpts
+        .iter()
+        .map(|trkpt| {
+            let elevation = trkpt.elevation_meters.and_then(|_| smoothed.next());
+            let from_elevation = previous.zip(elevation).and_then(|((time, before), after)| {
+                let seconds = seconds_between(time, trkpt.time);
+                (seconds > 0.0).then(|| (after - before) / seconds)
+            });
+            if let Some(elevation) = elevation {
+                previous = Some((trkpt.time, elevation));
+            }
+            trkpt.vertical_mps.or(from_elevation)
+        })
+        .collect()
+}
+
+// The power at each trkpt that has a speed.  Each covers the time since the
+// previous trkpt.
+pub fn powers(gpx: &Gpx, config: &PowerConfig) -> Vec<(DateTime<Utc>, f64)> {
+    let trkpts = gpx.trkpts();
+    trkpts
+        .iter()
+        .zip(vertical_speeds(trkpts, &config.filter))
+        .filter_map(|(trkpt, vertical_mps)| {
+            let meters_per_second = trkpt.meters_per_second?;
+            Some((
+                trkpt.time,
+                config.watts(meters_per_second, vertical_mps.unwrap_or(0.0)),
+            ))
+        })
+        .collect()
+}
+
+// Average and normalized power from start to stop; None when there's no
+// power then.  Seconds in gaps longer than MAX_GAP_SECONDS (pauses and
+// dropouts) count for nothing.
+pub fn power_between(
+    powers: &[(DateTime<Utc>, f64)],
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> Option<Power> {
+    // A watt reading a second, each the power at the end of that second
+    let first = powers.partition_point(|&(time, _)| time <= start);
+    let mut seconds = Vec::new();
+    let mut i = first;
+    let mut when = start;
+    while when < stop {
+        when += chrono::TimeDelta::seconds(1);
+        while i < powers.len() && powers[i].0 < when {
+            i += 1;
+        }
+        let Some(&(time, watts)) = powers.get(i) else {
+            break;
+        };
+        let previous = i.checked_sub(1).map_or(start, |j| powers[j].0);
+        if seconds_between(previous, time) <= MAX_GAP_SECONDS {
+            seconds.push(watts);
+        }
+    }
+    if seconds.is_empty() {
+        return None;
+    }
+
+    let average = seconds.iter().sum::<f64>() / seconds.len() as f64;
+    let normalized = if seconds.len() < ROLLING_SECONDS {
+        average
+    } else {
+        let rolling = seconds
+            .windows(ROLLING_SECONDS)
+            .map(|w| w.iter().sum::<f64>() / ROLLING_SECONDS as f64)
+            .collect::<Vec<_>>();
+        (rolling.iter().map(|p| p.powi(4)).sum::<f64>() / rolling.len() as f64).powf(0.25)
+    };
+    Some(Power {
+        average,
+        normalized,
+    })
+}
+
+impl PowerReport {
+    pub fn new(gpx: &Gpx, report: &IntervalReport, config: &PowerConfig) -> Self {
+        let powers = powers(gpx, config);
+        let trkpts = gpx.trkpts();
+        let activity = trkpts
+            .first()
+            .zip(trkpts.last())
+            .and_then(|(first, last)| power_between(&powers, first.time, last.time));
+        let intervals = report
+            .intervals
+            .iter()
+            .map(|i| (i.start(), power_between(&powers, i.start(), i.stop())))
+            .collect();
+
+        PowerReport {
+            origin: trkpts.first().map(|t| t.time),
+            activity,
+            intervals,
+        }
+    }
+}
+
+impl Display for PowerReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let watts = |power: &Option<Power>| {
+            power.map_or_else(
+                || format!("{:>7} {:>7}", "-", "-"),
+                |p| format!("{:7.0} {:7.0}", p.average, p.normalized),
+            )
+        };
+        writeln!(f, "{:>9} {:>7} {:>7}", "Start", "Watts", "NP")?;
+        writeln!(f, "{:>9} {}", "Activity", watts(&self.activity))?;
+        for (start, power) in &self.intervals {
+            let elapsed = seconds_between(self.origin.unwrap_or(*start), *start);
+            writeln!(f, "{:9.1} {}", Duration::from(elapsed), watts(power))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trkpts(speed: impl Fn(i64) -> f64, elevation: impl Fn(i64) -> f64) -> Gpx {
+        Gpx::from(
+            (0..=600)
+                .map(|second| {
+                    Trkpt::at(second)
+                        .with_speed(speed(second))
+                        .with_elevation(elevation(second))
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_watts() {
+        let config = PowerConfig::new(70.0);
+        assert!((config.watts(3.0, 0.0) - 70.0 * 3.0 * ECOR).abs() < 1e-9);
+        assert!(config.watts(3.0, 0.3) > 1.5 * config.watts(3.0, 0.0));
+        assert_eq!(0.0, config.watts(0.0, 0.0));
+
+        let windy = PowerConfig {
+            air: Some(Air {
+                headwind_mps: 2.0,
+                ..Default::default()
+            }),
+            ..config.clone()
+        };
+        let drag = 0.5 * 1.225 * 0.24 * 25.0 * 3.0;
+        assert!((windy.watts(3.0, 0.0) - config.watts(3.0, 0.0) - drag).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_power() {
+        let config = PowerConfig::new(70.0);
+        let flat = trkpts(|_| 3.0, |_| 1600.0);
+        let report = PowerReport::new(&flat, &IntervalReport::new(Vec::new()), &config);
+        let power = report.activity.unwrap();
+        assert!((power.average - 70.0 * 3.0 * ECOR).abs() < 1e-9);
+        assert!((power.normalized - power.average).abs() < 1e-9);
+
+        // A 10-minute pause isn't running, even at the speed after it.
+        let paused = Gpx::from(
+            (0..=100)
+                .chain(700..=1300)
+                .map(|second| {
+                    let speed = if second == 700 { 5.0 } else { 3.0 };
+                    Trkpt::at(second).with_speed(speed).with_elevation(1600.0)
+                })
+                .collect::<Vec<_>>(),
+        );
+        let pausing = PowerReport::new(&paused, &IntervalReport::new(Vec::new()), &config);
+        let power = pausing.activity.unwrap();
+        assert!((power.average - 70.0 * 3.0 * ECOR).abs() < 1e-9);
+        assert!((power.normalized - power.average).abs() < 1e-9);
+        let powers = powers(&paused, &config);
+        let at = |second| paused.trkpts()[0].time + chrono::TimeDelta::seconds(second);
+        let across = power_between(&powers, at(50), at(750)).unwrap();
+        assert!((across.average - 70.0 * 3.0 * ECOR).abs() < 1e-9);
+        assert!(power_between(&powers, at(100), at(700)).is_none());
+
+        // Climbing 5% from the elevations alone
+        let hill = trkpts(|_| 3.0, |second| 1600.0 + 0.15 * second as f64);
+        let climbing = PowerReport::new(&hill, &IntervalReport::new(Vec::new()), &config);
+        let expected = config.watts(3.0, 0.15);
+        assert!((climbing.activity.unwrap().average - expected).abs() < 2.0);
+
+        // Alternating minutes at 4 and 2 m/s average less than they
+        // normalize to.
+        let surges = trkpts(
+            |second| if (second - 1) / 60 % 2 == 0 { 4.0 } else { 2.0 },
+            |_| 1600.0,
+        );
+        let surging = PowerReport::new(&surges, &IntervalReport::new(Vec::new()), &config);
+        let power = surging.activity.unwrap();
+        assert!((power.average - 70.0 * 3.0 * ECOR).abs() < 1e-9);
+        assert!(power.normalized > power.average + 10.0);
+    }
+}
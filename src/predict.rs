@@ -0,0 +1,225 @@
+// Race time predictions from intervals and best efforts, two ways:
+//
+// Riegel's model, seconds = a·meters^b, is fit to the efforts in log-log
+// space.  b is only fit when the efforts' distances vary enough to pin it
+// down (and kept between MIN_EXPONENT and MAX_EXPONENT); otherwise it's
+// Riegel's 1.06.
+//
+// The critical speed model (see critical_speed.rs) predicts the time to
+// run a distance as the time it takes to cover whatever's left after D′ at
+// CS.  It's meant for races of up to about 20 minutes and predicts longer
+// ones too fast.
+//
+// The ranges are 95% prediction intervals from each fit's scatter, so they
+// need at least a couple more efforts than the fit has parameters, and
+// they say nothing about whether the efforts were run all out.
+
+use {
+    crate::{
+        best::Effort,
+        critical_speed::{self, CriticalSpeed, LinearFit, Performance},
+        gpx::METERS_PER_MILE,
+        interval_parse,
+    },
+    digital_duration_nom::duration::Duration,
+    std::{
+        fmt::{self, Display, Formatter},
+        time,
+    },
+};
+
+const RIEGEL_EXPONENT: f64 = 1.06;
+const MIN_EXPONENT: f64 = 1.0;
+const MAX_EXPONENT: f64 = 1.2;
+
+// In meters: the mile, 5 km, 10 km, half marathon and marathon
+pub const RACES: [f64; 5] = [METERS_PER_MILE, 5000.0, 10_000.0, 21_097.5, 42_195.0];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Riegel {
+    // ln(seconds) against ln(meters)
+    fit: LinearFit,
+    // Whether the exponent was fit rather than assumed
+    pub fitted: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Prediction {
+    pub seconds: f64,
+    // The 95% range, when there's enough to go on
+    pub range: Option<(f64, f64)>,
+}
+
+#[derive(Debug)]
+pub struct Predictions {
+    pub riegel: Option<Riegel>,
+    pub critical_speed: Option<CriticalSpeed>,
+    // Each race's meters and what the two models predict for it
+    pub races: Vec<(f64, Option<Prediction>, Option<Prediction>)>,
+}
+
+impl Riegel {
+    // None without any performances.
+    pub fn fit(performances: &[Performance]) -> Option<Self> {
+        let points = performances
+            .iter()
+            .filter(|p| p.meters > 0.0 && p.seconds > 0.0)
+            .map(|p| (p.meters.ln(), p.seconds.ln()))
+            .collect::<Vec<_>>();
+        let fitted = critical_speed::spans(performances.iter().map(|p| p.meters))
+            .then(|| LinearFit::new(&points))
+            .flatten()
+            .filter(|fit| (MIN_EXPONENT..=MAX_EXPONENT).contains(&fit.slope));
+        match fitted {
+            Some(fit) => Some(Riegel { fit, fitted: true }),
+            None => LinearFit::with_slope(&points, RIEGEL_EXPONENT)
+                .map(|fit| Riegel { fit, fitted: false }),
+        }
+    }
+
+    pub fn exponent(&self) -> f64 {
+        self.fit.slope
+    }
+
+    pub fn efforts(&self) -> usize {
+        self.fit.n
+    }
+
+    pub fn predict(&self, meters: f64) -> Prediction {
+        let x = meters.ln();
+        let y = self.fit.y(x);
+        Prediction {
+            seconds: y.exp(),
+            range: self
+                .fit
+                .margin(x)
+                .map(|margin| ((y - margin).exp(), (y + margin).exp())),
+        }
+    }
+}
+
+// The pace(duration) pairs of an interval log like assets/Intervals.txt
+pub fn performances_from_log(contents: &str) -> Vec<Performance> {
+    interval_parse::many_pace_duration_pairs(contents)
+        .map_or_else(|_| Vec::new(), |(_, pairs)| pairs)
+        .into_iter()
+        .map(|(pace, duration)| {
+            Performance::from_pace(
+                time::Duration::from(pace).as_secs_f64(),
+                time::Duration::from(duration).as_secs_f64(),
+            )
+        })
+        .collect()
+}
+
+pub fn predict(performances: &[Performance], races: &[f64]) -> Predictions {
+    let riegel = Riegel::fit(performances);
+    let critical_speed = CriticalSpeed::fit(performances);
+    let races = races
+        .iter()
+        .map(|&meters| {
+            (
+                meters,
+                riegel.as_ref().map(|r| r.predict(meters)),
+                critical_speed
+                    .as_ref()
+                    .and_then(|cs| cs.seconds_for(meters))
+                    .map(|(seconds, range)| Prediction { seconds, range }),
+            )
+        })
+        .collect();
+    Predictions {
+        riegel,
+        critical_speed,
+        races,
+    }
+}
+
+impl Display for Predictions {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.riegel {
+            Some(riegel) => writeln!(
+                f,
+                "Riegel exponent {:.3} ({} from {} efforts)",
+                riegel.exponent(),
+                if riegel.fitted { "fit" } else { "assumed" },
+                riegel.efforts()
+            )?,
+            None => writeln!(f, "No efforts to predict from")?,
+        }
+        match &self.critical_speed {
+            Some(cs) => writeln!(f, "{cs}")?,
+            None => writeln!(
+                f,
+                "Not enough efforts of different lengths for critical speed"
+            )?,
+        }
+
+        let column = |prediction: &Option<Prediction>| match prediction {
+            None => format!("{:>10} {:>21}", "-", ""),
+            Some(p) => {
+                let range = p.range.map_or_else(String::new, |(low, high)| {
+                    format!("{:.0}-{:.0}", Duration::from(low), Duration::from(high))
+                });
+                format!("{:10.0} {range:>21}", Duration::from(p.seconds))
+            }
+        };
+        writeln!(f, "{:>8} {:>10} {:>32}", "Race", "Riegel", "CS")?;
+        for (meters, riegel, cs) in &self.races {
+            let race = Effort::Meters(*meters);
+            let line = format!("{race:>8} {} {}", column(riegel), column(cs));
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_riegel() {
+        // Exactly Riegel with b = 1.08, from 1.5 km through 10 km
+        let performances = [1500.0, 3000.0, 5000.0, 10_000.0].map(|meters| Performance {
+            meters,
+            seconds: 300.0 * (meters / 1500.0_f64).powf(1.08),
+        });
+        let riegel = Riegel::fit(&performances).unwrap();
+        assert!(riegel.fitted);
+        assert!((riegel.exponent() - 1.08).abs() < 1e-9);
+        let half = riegel.predict(21_097.5);
+        assert!((half.seconds - 300.0 * (21_097.5 / 1500.0_f64).powf(1.08)).abs() < 1e-6);
+
+        // Efforts of about the same length keep Riegel's exponent.
+        let riegel = Riegel::fit(&performances[..1]).unwrap();
+        assert!(!riegel.fitted);
+        assert_eq!(RIEGEL_EXPONENT, riegel.exponent());
+        assert_eq!(None, riegel.predict(5000.0).range);
+        assert_eq!(None, Riegel::fit(&[]));
+    }
+
+    #[test]
+    fn test_predict_from_intervals() {
+        let contents = crate::misc::contents_from("assets/Intervals.txt".as_ref()).unwrap();
+        let performances = performances_from_log(&contents);
+        let predictions = predict(&performances, &RACES);
+
+        // The reps are all about 75 seconds, too alike to fit anything, so
+        // only Riegel, assuming the standard exponent, predicts anything.
+        assert!(!predictions.riegel.as_ref().unwrap().fitted);
+        assert_eq!(None, predictions.critical_speed);
+        let (race, riegel, _) = predictions.races[1];
+        assert_eq!(5000.0, race);
+        let riegel = riegel.unwrap();
+        let (low, high) = riegel.range.unwrap();
+        assert!(low < riegel.seconds && riegel.seconds < high);
+        // Reps at around 7:00/mi don't make for a 5 km much faster than
+        // 25 minutes.
+        let five_k = Duration::from(riegel.seconds);
+        assert!(
+            (20.0 * 60.0..30.0 * 60.0).contains(&riegel.seconds),
+            "{five_k:.1}"
+        );
+    }
+}
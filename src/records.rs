@@ -0,0 +1,573 @@
+// A personal-records database: the best efforts (see best.rs) and a few
+// summary numbers for every activity in a directory, kept in a text file so
+// that re-running only reads the activities that are new or have changed.
+//
+// After a header line, the file has a line per activity, each followed by
+// a line per best effort of that activity, with tab-separated fields:
+//
+//     activity  modified  start  seconds  meters  path
+//     effort    effort    start  seconds  meters
+//
+// Times are milliseconds since the Unix epoch, except modified, which is
+// the file's modification time in seconds.  The path comes last so that it
+// can contain anything but a newline.
+//
+// Movescount (and others) export one activity in several formats, so
+// activities that start within a minute of one another are copies of the
+// same one.  Every copy keeps its efforts, but only the earliest starting
+// copy's count (the first path breaks a tie), so that modifying or removing
+// any of them still leaves one that counts.
+
+use {
+    crate::{
+        best::{self, BestEffort, Effort},
+        critical_speed::Performance,
+        gpx::{Gpx, METERS_PER_MILE, seconds_between},
+        misc, splits,
+    },
+    chrono::{DateTime, Datelike, Utc},
+    digital_duration_nom::duration::Duration,
+    std::{
+        cmp::Ordering,
+        fmt::{self, Display, Formatter},
+        fs, io,
+        path::{Path, PathBuf},
+        str::FromStr,
+        time::UNIX_EPOCH,
+    },
+};
+
+const HEADER: &str = "# nom_fun records 1";
+pub(crate) const DUPLICATE_SECONDS: f64 = 60.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffortRecord {
+    pub effort: Effort,
+    pub start: DateTime<Utc>,
+    pub seconds: f64,
+    pub meters: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Activity {
+    pub path: PathBuf,
+    // Seconds since the Unix epoch
+    pub modified: u64,
+    pub start: DateTime<Utc>,
+    pub seconds: f64,
+    pub meters: f64,
+    pub efforts: Vec<EffortRecord>,
+    // A copy of the activity before it, so its efforts don't count
+    pub duplicate: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Records {
+    // Oldest first
+    pub activities: Vec<Activity>,
+}
+
+// An effort that beat every other activity's, when it was added
+#[derive(Debug)]
+pub struct NewRecord {
+    pub path: PathBuf,
+    pub record: EffortRecord,
+    pub previous: Option<(PathBuf, EffortRecord)>,
+}
+
+#[derive(Debug, Default)]
+pub struct Update {
+    pub added: usize,
+    pub removed: usize,
+    pub new_records: Vec<NewRecord>,
+    pub unreadable: Vec<(PathBuf, io::Error)>,
+}
+
+#[derive(Debug)]
+pub struct Leaderboard<'a> {
+    pub effort: Effort,
+    // Best first
+    pub entries: Vec<(&'a Activity, &'a EffortRecord)>,
+}
+
+impl EffortRecord {
+    // Per mile
+    pub fn pace(&self) -> Duration {
+        Duration::from(self.seconds / self.meters * METERS_PER_MILE)
+    }
+
+    // Less is better: fewer seconds for a distance, more meters for a
+    // duration.
+    fn compare(&self, other: &Self) -> Ordering {
+        match self.effort {
+            Effort::Meters(_) => self.seconds.total_cmp(&other.seconds),
+            Effort::Seconds(_) => other.meters.total_cmp(&self.meters),
+        }
+    }
+}
+
+impl From<BestEffort> for EffortRecord {
+    fn from(best: BestEffort) -> Self {
+        EffortRecord {
+            effort: best.effort,
+            start: best.start(),
+            seconds: best.seconds(),
+            meters: best.meters(),
+        }
+    }
+}
+
+impl Activity {
+    // None when gpx has no trkpts.
+    pub fn new(path: PathBuf, modified: u64, gpx: &Gpx) -> Option<Self> {
+        let trkpts = gpx.trkpts();
+        let (first, last) = (trkpts.first()?, trkpts.last()?);
+        Some(Activity {
+            path,
+            modified,
+            start: first.time,
+            seconds: seconds_between(first.time, last.time),
+            meters: splits::total_meters(gpx),
+            efforts: best::best_efforts(gpx, &Effort::STANDARD)
+                .efforts
+                .into_iter()
+                .map(EffortRecord::from)
+                .collect(),
+            duplicate: false,
+        })
+    }
+}
+
+// Every activity file under directory, with its modification time
+fn activity_files(directory: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    misc::activity_paths(directory)?
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            Ok((path, modified))
+        })
+        .collect()
+}
+
+impl Records {
+    // An empty database when there's no file yet (or it's an old one, so
+    // that every activity gets read again)
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => contents.parse().map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Records::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    // Marks all but the first of each run of activities that start within
+    // DUPLICATE_SECONDS of the one before as duplicates.
+    fn find_duplicates(&mut self) {
+        let mut previous = None;
+        for a in &mut self.activities {
+            a.duplicate =
+                previous.is_some_and(|start| seconds_between(start, a.start) < DUPLICATE_SECONDS);
+            previous = Some(a.start);
+        }
+    }
+
+    // Adds activity (replacing any earlier version of the same file) and
+    // returns its efforts that are better than any other activity's.  A
+    // copy of an activity that's already there sets no new records.
+    pub fn add(&mut self, activity: Activity) -> Vec<NewRecord> {
+        self.activities.retain(|a| a.path != activity.path);
+        let copy = self
+            .activities
+            .iter()
+            .any(|a| seconds_between(a.start, activity.start).abs() < DUPLICATE_SECONDS);
+
+        let new_records = activity
+            .efforts
+            .iter()
+            .filter(|_| !copy)
+            .filter_map(|record| {
+                let previous = self
+                    .leaderboard(record.effort, None)
+                    .entries
+                    .first()
+                    .copied();
+                match previous {
+                    Some((_, best)) if record.compare(best).is_ge() => None,
+                    _ => Some(NewRecord {
+                        path: activity.path.clone(),
+                        record: record.clone(),
+                        previous: previous.map(|(a, best)| (a.path.clone(), best.clone())),
+                    }),
+                }
+            })
+            .collect();
+
+        let at = self
+            .activities
+            .partition_point(|a| (a.start, &a.path) <= (activity.start, &activity.path));
+        self.activities.insert(at, activity);
+        self.find_duplicates();
+        new_records
+    }
+
+    // Brings the database up to date with the activity files under
+    // directory, reading only those that are new or have been modified.
+    // New activities are added oldest first, so the new records are the
+    // ones that were records when they were run.
+    pub fn update(&mut self, directory: &Path) -> io::Result<Update> {
+        let files = activity_files(directory)?;
+        let mut update = Update::default();
+
+        let before = self.activities.len();
+        self.activities
+            .retain(|a| files.iter().any(|(path, _)| *path == a.path));
+        update.removed = before - self.activities.len();
+        self.find_duplicates();
+
+        let mut activities = Vec::new();
+        for (path, modified) in files {
+            if self
+                .activities
+                .iter()
+                .any(|a| a.path == path && a.modified == modified)
+            {
+                continue;
+            }
+            match misc::activity_with_speeds_from(&path) {
+                Ok(gpx) => activities.extend(Activity::new(path, modified, &gpx)),
+                Err(e) => update.unreadable.push((path, e)),
+            }
+        }
+        activities.sort_by_key(|a| a.start);
+        update.added = activities.len();
+        for activity in activities {
+            update.new_records.extend(self.add(activity));
+        }
+        Ok(update)
+    }
+
+    // Every activity's best effort, best first, optionally only those run
+    // in year (in the local time zone).
+    pub fn leaderboard(&self, effort: Effort, year: Option<i32>) -> Leaderboard<'_> {
+        let mut entries = self
+            .activities
+            .iter()
+            .filter(|a| !a.duplicate)
+            .flat_map(|a| a.efforts.iter().map(move |record| (a, record)))
+            .filter(|(_, record)| record.effort == effort)
+            .filter(|(_, record)| {
+                year.is_none_or(|year| record.start.with_timezone(crate::tz()).year() == year)
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|(_, a), (_, b)| a.compare(b));
+        Leaderboard { effort, entries }
+    }
+
+    // The record for each standard effort, e.g., to fit critical speed to
+    pub fn best_performances(&self, year: Option<i32>) -> Vec<Performance> {
+        Effort::STANDARD
+            .iter()
+            .filter_map(|&effort| {
+                self.leaderboard(effort, year)
+                    .entries
+                    .first()
+                    .map(|(_, record)| Performance {
+                        meters: record.meters,
+                        seconds: record.seconds,
+                    })
+            })
+            .collect()
+    }
+}
+
+impl Display for Records {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        for a in &self.activities {
+            writeln!(
+                f,
+                "activity\t{}\t{}\t{}\t{}\t{}",
+                a.modified,
+                a.start.timestamp_millis(),
+                a.seconds,
+                a.meters,
+                a.path.display()
+            )?;
+            for e in &a.efforts {
+                writeln!(
+                    f,
+                    "effort\t{}\t{}\t{}\t{}",
+                    e.effort,
+                    e.start.timestamp_millis(),
+                    e.seconds,
+                    e.meters
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Records {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut lines = string.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(format!("not a records file (expected \"{HEADER}\" first)"));
+        }
+        let mut records = Records::default();
+
+        for (i, line) in lines {
+            let error = |what: &str| format!("line {}: {what}", i + 1);
+            let number = |field: Option<&str>| {
+                field
+                    .and_then(|f| f.parse::<f64>().ok())
+                    .ok_or_else(|| error("bad number"))
+            };
+            let time = |field: Option<&str>| {
+                field
+                    .and_then(|f| f.parse().ok())
+                    .and_then(DateTime::from_timestamp_millis)
+                    .ok_or_else(|| error("bad time"))
+            };
+            let mut fields = line.splitn(6, '\t');
+            match fields.next() {
+                Some("activity") => records.activities.push(Activity {
+                    modified: fields
+                        .next()
+                        .and_then(|f| f.parse().ok())
+                        .ok_or_else(|| error("bad modification time"))?,
+                    start: time(fields.next())?,
+                    seconds: number(fields.next())?,
+                    meters: number(fields.next())?,
+                    path: fields.next().ok_or_else(|| error("no path"))?.into(),
+                    efforts: Vec::new(),
+                    duplicate: false,
+                }),
+                Some("effort") => {
+                    let record = EffortRecord {
+                        effort: fields
+                            .next()
+                            .ok_or_else(|| error("no effort"))?
+                            .parse()
+                            .map_err(|e: String| error(&e))?,
+                        start: time(fields.next())?,
+                        seconds: number(fields.next())?,
+                        meters: number(fields.next())?,
+                    };
+                    records
+                        .activities
+                        .last_mut()
+                        .ok_or_else(|| error("effort before any activity"))?
+                        .efforts
+                        .push(record);
+                }
+                _ => return Err(error("expected activity or effort")),
+            }
+        }
+        records
+            .activities
+            .sort_by(|a, b| (a.start, &a.path).cmp(&(b.start, &b.path)));
+        records.find_duplicates();
+        Ok(records)
+    }
+}
+
+impl Display for NewRecord {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let r = &self.record;
+        write!(
+            f,
+            "New {} record: {:.1} ({:.1}/mi, {:.0} m) in {}",
+            r.effort,
+            Duration::from(r.seconds),
+            r.pace(),
+            r.meters,
+            self.path.display()
+        )?;
+        if let Some((path, previous)) = &self.previous {
+            write!(
+                f,
+                ", was {:.1} ({:.1}/mi, {:.0} m) in {}",
+                Duration::from(previous.seconds),
+                previous.pace(),
+                previous.meters,
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Leaderboard<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>3} {:>10} {:>9} {:>7} {:>7}  File",
+            "#", "Date", self.effort, "Pace", "Meters"
+        )?;
+        for (rank, (activity, record)) in self.entries.iter().enumerate() {
+            writeln!(
+                f,
+                "{:3} {:>10} {:9.1} {:7.1} {:7.0}  {}",
+                rank + 1,
+                record.start.with_timezone(crate::tz()).date_naive(),
+                Duration::from(record.seconds),
+                record.pace(),
+                record.meters,
+                activity.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::gpx::Trkpt};
+
+    // A run at meters_per_second for seconds, starting days after the
+    // first of December, 2018
+    fn run(days: i64, meters_per_second: f64, seconds: i64) -> Gpx {
+        let start = 1_543_647_600 + days * 24 * 60 * 60;
+        Gpx::from(
+            (0..=seconds)
+                .map(|second| Trkpt::at_timestamp(start + second).with_speed(meters_per_second))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn activity(path: &str, days: i64, meters_per_second: f64) -> Activity {
+        Activity::new(path.into(), 0, &run(days, meters_per_second, 1800)).unwrap()
+    }
+
+    #[test]
+    fn test_new_records() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let mut records = Records::default();
+
+        // 30 minutes is enough for everything up to 5 km.
+        let first = records.add(activity("a.gpx", 0, 3.0));
+        assert_eq!(7, first.len());
+        assert!(first.iter().all(|r| r.previous.is_none()));
+
+        assert!(records.add(activity("b.gpx", 1, 2.5)).is_empty());
+        let faster = records.add(activity("c.gpx", 60, 3.5));
+        assert_eq!(7, faster.len());
+        assert!(
+            faster
+                .iter()
+                .all(|r| r.previous.as_ref().unwrap().0 == Path::new("a.gpx"))
+        );
+        // Another export of the same run doesn't count.
+        let mut copy = activity("c.fit", 60, 3.5);
+        copy.start += chrono::TimeDelta::seconds(2);
+        assert!(records.add(copy).is_empty());
+
+        let mile = Effort::Meters(METERS_PER_MILE);
+        let all = records.leaderboard(mile, None).entries;
+        let paths = all.iter().map(|(a, _)| a.path.to_str().unwrap());
+        assert_eq!(vec!["c.gpx", "a.gpx", "b.gpx"], paths.collect::<Vec<_>>());
+        // Day 60 is in 2019.
+        let in_2018 = records.leaderboard(mile, Some(2018)).entries;
+        assert_eq!(Path::new("a.gpx"), in_2018[0].0.path);
+        assert_eq!(2, in_2018.len());
+        let minutes = records.leaderboard(Effort::Seconds(5 * 60), None).entries;
+        assert!((minutes[0].1.meters - 3.5 * 300.0).abs() < 1e-6);
+
+        // All at 3.5 m/s, to the millisecond: the 7 efforts that fit in 30
+        // minutes
+        let best = records.best_performances(None);
+        assert_eq!(7, best.len());
+        assert!(
+            best.iter()
+                .all(|p| (p.meters_per_second() - 3.5).abs() < 1e-4)
+        );
+    }
+
+    #[test]
+    fn test_file_format() {
+        let mut records = Records::default();
+        records.add(activity("runs/a b.gpx", 0, 3.0));
+        records.add(activity("runs/c\td.fit", 3, 2.9));
+        let text = records.to_string();
+        assert_eq!(Ok(records), text.parse());
+
+        assert!("activity\t0\t0\t0\t0\tx".parse::<Records>().is_err());
+        let bad = format!("{HEADER}\neffort\tmile\t0\t400\t1609.344\n");
+        assert_eq!(
+            Err("line 2: effort before any activity".to_string()),
+            bad.parse::<Records>()
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let mut records = Records::default();
+        let update = records.update("assets".as_ref()).unwrap();
+
+        // The FIT, KML and xlsx are all the same run.
+        assert_eq!(3, update.added);
+        assert_eq!(
+            1,
+            records.activities.iter().filter(|a| !a.duplicate).count()
+        );
+        assert_eq!(10, update.new_records.len());
+
+        let again = records.update("assets".as_ref()).unwrap();
+        assert_eq!(0, again.added);
+        assert!(again.new_records.is_empty());
+    }
+
+    #[test]
+    fn test_update_copies() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let directory = std::env::temp_dir().join(format!("records-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for extension in ["fit", "kml", "xlsx"] {
+            let name = format!("Move_2018_12_17_06_59_29_Running.{extension}");
+            fs::copy(Path::new("assets").join(&name), directory.join(&name)).unwrap();
+        }
+        let mut records = Records::default();
+        records.update(&directory).unwrap();
+        let mile = Effort::Meters(METERS_PER_MILE);
+        let counted = |records: &Records| {
+            let entries = records.leaderboard(mile, None).entries;
+            assert_eq!(1, entries.len());
+            entries[0].0.path.clone()
+        };
+
+        // Modifying the copy that counts leaves one that counts.
+        let first = counted(&records);
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let update = records.update(&directory).unwrap();
+        assert_eq!(1, update.added);
+        assert!(update.new_records.is_empty());
+        assert_eq!(first, counted(&records));
+
+        // ... and so does removing it.
+        fs::remove_file(&first).unwrap();
+        let update = records.update(&directory).unwrap();
+        assert_eq!(1, update.removed);
+        assert_ne!(first, counted(&records));
+
+        let text = records.to_string();
+        assert_eq!(Ok(records), text.parse());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,445 @@
+// Speeds computed from positions, for activities (e.g., most GPX files)
+// that don't come with them.
+//
+// Point-to-point speeds are noisy: GPS jitter moves a point a few meters
+// and a repeated timestamp divides by zero.  So each speed is first checked
+// for plausibility (a duration, a top speed and a top acceleration) and an
+// implausible one is replaced by interpolating between the plausible ones
+// on either side.  Then, optionally, the speeds are smoothed.  Each
+// replacement is reported, so it's clear which speeds weren't measured.
+
+use {
+    crate::gpx::{Trkpt, seconds_between},
+    chrono::{DateTime, Utc},
+    geo::{LineString, prelude::*},
+    std::{
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+    },
+};
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeedSmoothing {
+    None,
+    // The mean of the window speeds centered on each one
+    MovingAverage {
+        window: usize,
+    },
+    // A quadratic fit to the window speeds centered on each one, which
+    // keeps the peaks of short efforts better than a moving average.
+    SavitzkyGolay {
+        window: usize,
+    },
+    // Constant velocity seen through GPS noise, filtered on position:
+    // process_noise is the variance (m²/s⁴) of the acceleration and
+    // measurement_noise the variance (m²) of each position.  The speed is
+    // that of the filtered velocity, so it ignores elevation.
+    Kalman {
+        process_noise: f64,
+        measurement_noise: f64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct SpeedConfig {
+    pub smoothing: SpeedSmoothing,
+    pub max_meters_per_second: f64,
+    pub max_acceleration: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    // No time passed since the previous position
+    SameTime,
+    // Faster than max_meters_per_second
+    TooFast,
+    // Changed from the last plausible speed faster than max_acceleration
+    TooSudden,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Correction {
+    pub time: DateTime<Utc>,
+    // None when the speed couldn't be computed at all
+    pub raw: Option<f64>,
+    pub corrected: f64,
+    pub reason: Reason,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> Self {
+        SpeedConfig {
+            smoothing: SpeedSmoothing::None,
+            // Faster than anyone has run and than any runner accelerates
+            max_meters_per_second: 12.5,
+            max_acceleration: 6.0,
+        }
+    }
+}
+
+impl SpeedSmoothing {
+    pub const MOVING_AVERAGE: Self = SpeedSmoothing::MovingAverage { window: 5 };
+    pub const SAVITZKY_GOLAY: Self = SpeedSmoothing::SavitzkyGolay { window: 7 };
+    pub const KALMAN: Self = SpeedSmoothing::Kalman {
+        process_noise: 1.0,
+        measurement_noise: 16.0,
+    };
+}
+
+impl FromStr for SpeedSmoothing {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "none" => Ok(SpeedSmoothing::None),
+            "moving-average" => Ok(SpeedSmoothing::MOVING_AVERAGE),
+            "savitzky-golay" => Ok(SpeedSmoothing::SAVITZKY_GOLAY),
+            "kalman" => Ok(SpeedSmoothing::KALMAN),
+            _ => Err(format!(
+                "unknown speed smoothing \"{string}\" (expected none, moving-average, savitzky-golay or kalman)"
+            )),
+        }
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let text = match self {
+            Reason::SameTime => "same time as the previous point",
+            Reason::TooFast => "too fast",
+            Reason::TooSudden => "too sudden a change",
+        };
+        f.write_str(text)
+    }
+}
+
+impl Display for Correction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let raw = self
+            .raw
+            .map_or_else(|| "-".to_string(), |raw| format!("{raw:.2}"));
+        write!(
+            f,
+            "{} {raw} -> {:.2} m/s ({})",
+            self.time.with_timezone(crate::tz()),
+            self.corrected,
+            self.reason
+        )
+    }
+}
+
+// A trkpt with a position
+#[derive(Clone, Copy, Debug)]
+struct Fix {
+    index: usize,
+    time: DateTime<Utc>,
+    lat: f64,
+    lon: f64,
+    elevation: Option<f64>,
+}
+
+fn raw_speed(from: &Fix, to: &Fix) -> f64 {
+    let length_2d = Haversine.length(&LineString::<f64>::from(vec![
+        (from.lon, from.lat),
+        (to.lon, to.lat),
+    ]));
+    let length_3d = match (to.elevation, from.elevation) {
+        (Some(em1), Some(em2)) => (length_2d.powi(2) + (em1 - em2).powi(2)).sqrt(),
+        _ => length_2d,
+    };
+    length_3d / seconds_between(from.time, to.time)
+}
+
+// Why speeds[i] (the speed into fixes[i + 1]) isn't plausible, if it isn't.
+fn implausible(config: &SpeedConfig, fixes: &[Fix], speeds: &[f64]) -> Vec<Option<Reason>> {
+    let mut reasons = Vec::with_capacity(speeds.len());
+    let mut last_plausible: Option<(DateTime<Utc>, f64)> = None;
+
+    for (i, &speed) in speeds.iter().enumerate() {
+        let time = fixes[i + 1].time;
+        let reason = if !speed.is_finite() {
+            Some(Reason::SameTime)
+        } else if speed > config.max_meters_per_second {
+            Some(Reason::TooFast)
+        } else if let Some((last_time, last_speed)) = last_plausible
+            && (speed - last_speed).abs()
+                > config.max_acceleration * seconds_between(last_time, time)
+        {
+            Some(Reason::TooSudden)
+        } else {
+            None
+        };
+        if reason.is_none() {
+            last_plausible = Some((time, speed));
+        }
+        reasons.push(reason);
+    }
+    reasons
+}
+
+// Replaces the speeds that have a reason with ones interpolated (by time)
+// between the plausible speeds on either side.
+fn interpolate(fixes: &[Fix], speeds: &mut [f64], reasons: &[Option<Reason>]) {
+    let plausible = (0..speeds.len())
+        .filter(|&i| reasons[i].is_none())
+        .collect::<Vec<_>>();
+    for i in (0..speeds.len()).filter(|&i| reasons[i].is_some()) {
+        let after = plausible.partition_point(|&p| p < i);
+        speeds[i] = match (
+            after.checked_sub(1).map(|b| plausible[b]),
+            plausible.get(after),
+        ) {
+            (Some(before), Some(&after)) => {
+                let time = |i: usize| fixes[i + 1].time;
+                let fraction = seconds_between(time(before), time(i))
+                    / seconds_between(time(before), time(after));
+                speeds[before] + (speeds[after] - speeds[before]) * fraction
+            }
+            (Some(before), None) => speeds[before],
+            (None, Some(&after)) => speeds[after],
+            (None, None) => 0.0,
+        };
+    }
+}
+
+fn moving_average(window: usize, speeds: &[f64]) -> Vec<f64> {
+    let n = speeds.len();
+    (0..n)
+        .map(|i| {
+            let nearby = &speeds[i.saturating_sub(window / 2)..(i + window / 2 + 1).min(n)];
+            nearby.iter().sum::<f64>() / nearby.len() as f64
+        })
+        .collect()
+}
+
+// The quadratic (and cubic) Savitzky–Golay smoothing coefficient for
+// offset i in a window of 2m + 1
+fn savitzky_golay_coefficient(m: usize, i: isize) -> f64 {
+    let m = m as f64;
+    let i = i as f64;
+    (3.0 * (3.0 * m * m + 3.0 * m - 1.0) - 15.0 * i * i)
+        / ((2.0 * m + 1.0) * (4.0 * m * m + 4.0 * m - 3.0))
+}
+
+fn savitzky_golay(window: usize, speeds: &[f64]) -> Vec<f64> {
+    let n = speeds.len();
+    (0..n)
+        .map(|i| {
+            // The window shrinks near the ends, so it's always centered.
+            let m = (window / 2).min(i).min(n - 1 - i);
+            if m == 0 {
+                return speeds[i];
+            }
+            let smoothed = (-(m as isize)..=m as isize)
+                .map(|o| savitzky_golay_coefficient(m, o) * speeds[(i as isize + o) as usize])
+                .sum::<f64>();
+            smoothed.max(0.0)
+        })
+        .collect()
+}
+
+// The speed into each fix after the first, from a constant-velocity Kalman
+// filter on the fixes' positions (in meters east and north of the first).
+// Fixes whose speeds were implausible are predicted but not measured.
+fn kalman(
+    process_noise: f64,
+    measurement_noise: f64,
+    fixes: &[Fix],
+    reasons: &[Option<Reason>],
+) -> Vec<f64> {
+    let origin = fixes[0];
+    let cos_lat = origin.lat.to_radians().cos();
+    let position = |fix: &Fix| {
+        [
+            EARTH_RADIUS_METERS * (fix.lon - origin.lon).to_radians() * cos_lat,
+            EARTH_RADIUS_METERS * (fix.lat - origin.lat).to_radians(),
+        ]
+    };
+    // For each axis, position and velocity and their covariance
+    let mut state = position(&origin).map(|p| ([p, 0.0], [[measurement_noise, 0.0], [0.0, 100.0]]));
+    let mut speeds = Vec::with_capacity(fixes.len() - 1);
+
+    for (i, fix) in fixes.iter().enumerate().skip(1) {
+        let dt = seconds_between(fixes[i - 1].time, fix.time);
+        let measured = position(fix);
+        for (axis, ([x, v], p)) in state.iter_mut().enumerate() {
+            // Predict
+            *x += *v * dt;
+            let q = process_noise;
+            let p00 = p[0][0] + dt * (p[0][1] + p[1][0]) + dt * dt * p[1][1] + q * dt.powi(4) / 4.0;
+            let p01 = p[0][1] + dt * p[1][1] + q * dt.powi(3) / 2.0;
+            let p11 = p[1][1] + q * dt * dt;
+            *p = [[p00, p01], [p01, p11]];
+            // Update
+            if reasons[i - 1].is_none() {
+                let s = p[0][0] + measurement_noise;
+                let (k0, k1) = (p[0][0] / s, p[1][0] / s);
+                let innovation = measured[axis] - *x;
+                *x += k0 * innovation;
+                *v += k1 * innovation;
+                *p = [
+                    [(1.0 - k0) * p[0][0], (1.0 - k0) * p[0][1]],
+                    [p[1][0] - k1 * p[0][0], p[1][1] - k1 * p[0][1]],
+                ];
+            }
+        }
+        speeds.push(state[0].0[1].hypot(state[1].0[1]));
+    }
+    speeds
+}
+
+// Fills in each trkpt's speed from its position and the previous one's,
+// returning the speeds that were implausible.  Trkpts without positions
+// keep whatever speed they came with.
+pub(crate) fn fill_in(config: &SpeedConfig, trkpts: &mut [Trkpt]) -> Vec<Correction> {
+    let fixes = trkpts
+        .iter()
+        .enumerate()
+        .filter_map(|(index, t)| {
+            Some(Fix {
+                index,
+                time: t.time,
+                lat: t.lat?,
+                lon: t.lon?,
+                elevation: t.elevation_meters,
+            })
+        })
+        .collect::<Vec<_>>();
+    if fixes.len() < 2 {
+        return Vec::new();
+    }
+
+    let raw = fixes
+        .windows(2)
+        .map(|pair| raw_speed(&pair[0], &pair[1]))
+        .collect::<Vec<_>>();
+    let reasons = implausible(config, &fixes, &raw);
+    let mut speeds = raw.clone();
+    interpolate(&fixes, &mut speeds, &reasons);
+    let speeds = match config.smoothing {
+        SpeedSmoothing::None => speeds,
+        SpeedSmoothing::MovingAverage { window } => moving_average(window, &speeds),
+        SpeedSmoothing::SavitzkyGolay { window } => savitzky_golay(window, &speeds),
+        SpeedSmoothing::Kalman {
+            process_noise,
+            measurement_noise,
+        } => kalman(process_noise, measurement_noise, &fixes, &reasons),
+    };
+
+    let mut corrections = Vec::new();
+    for (i, fix) in fixes.iter().enumerate().skip(1) {
+        trkpts[fix.index].meters_per_second = Some(speeds[i - 1]);
+        if let Some(reason) = reasons[i - 1] {
+            corrections.push(Correction {
+                time: fix.time,
+                raw: raw[i - 1].is_finite().then_some(raw[i - 1]),
+                corrected: speeds[i - 1],
+                reason,
+            });
+        }
+    }
+    corrections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Due north at 3 m/s, one fix a second
+    fn run(seconds: i64) -> Vec<Trkpt> {
+        let degrees_per_meter = 1.0 / EARTH_RADIUS_METERS.to_radians();
+        (0..seconds)
+            .map(|second| {
+                Trkpt::at(second)
+                    .with_position(35.0 + 3.0 * second as f64 * degrees_per_meter, -106.5)
+            })
+            .collect()
+    }
+
+    fn speeds(trkpts: &[Trkpt]) -> Vec<f64> {
+        trkpts[1..]
+            .iter()
+            .map(|t| t.meters_per_second.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_fill_in() {
+        let mut trkpts = run(60);
+        // A fix 30 m off the line, a repeated timestamp and a fix 8 m ahead
+        let degrees_per_meter = 1.0 / EARTH_RADIUS_METERS.to_radians();
+        trkpts[20].lon = trkpts[20].lon.map(|lon| lon + 30.0 * degrees_per_meter);
+        trkpts[40].time = trkpts[39].time;
+        trkpts[50].lat = trkpts[50].lat.map(|lat| lat + 8.0 * degrees_per_meter);
+        let corrections = fill_in(&SpeedConfig::default(), &mut trkpts);
+
+        assert_eq!(None, trkpts[0].meters_per_second);
+        let reasons = corrections.iter().map(|c| c.reason).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Reason::TooFast,
+                Reason::TooFast,
+                Reason::SameTime,
+                Reason::TooSudden
+            ],
+            reasons
+        );
+        assert_eq!(trkpts[20].time, corrections[0].time);
+        assert_eq!(None, corrections[2].raw);
+        assert!((corrections[3].raw.unwrap() - 11.0).abs() < 1e-3);
+        assert!(
+            corrections[..3]
+                .iter()
+                .all(|c| (c.corrected - 3.0).abs() < 1e-3)
+        );
+        // Between the 3 m/s before it and the 5 m/s after it
+        assert!((corrections[3].corrected - 4.0).abs() < 1e-3);
+        // Besides that, the speeds after the repeated timestamp (half what
+        // it should be) and after the fix that was ahead are plausible
+        // enough to keep.
+        let speeds = speeds(&trkpts);
+        assert_eq!(3, speeds.iter().filter(|s| (*s - 3.0).abs() > 1e-3).count());
+    }
+
+    #[test]
+    fn test_smoothing() {
+        for smoothing in [
+            SpeedSmoothing::MOVING_AVERAGE,
+            SpeedSmoothing::SAVITZKY_GOLAY,
+            SpeedSmoothing::KALMAN,
+        ] {
+            // A steady run with a fix 3 m off the line, which is jitter
+            // rather than anything implausible.
+            let mut trkpts = run(60);
+            let degrees_per_meter = 1.0 / EARTH_RADIUS_METERS.to_radians();
+            trkpts[30].lon = trkpts[30].lon.map(|lon| lon + 3.0 * degrees_per_meter);
+            let config = SpeedConfig {
+                smoothing,
+                ..Default::default()
+            };
+            assert!(fill_in(&config, &mut trkpts).is_empty());
+
+            let raw_error = (4.243 - 3.0) / 3.0;
+            let error = speeds(&trkpts)[20..40]
+                .iter()
+                .map(|s| (s - 3.0).abs() / 3.0)
+                .fold(0.0, f64::max);
+            assert!(error < 0.7 * raw_error, "{smoothing:?} {error}");
+        }
+    }
+
+    #[test]
+    fn test_savitzky_golay() {
+        // A quadratic fit leaves a quadratic alone.
+        let speeds = (0..20).map(|i| 0.01 * (i * i) as f64).collect::<Vec<_>>();
+        let smoothed = savitzky_golay(7, &speeds);
+        assert!(
+            speeds
+                .iter()
+                .zip(smoothed)
+                .all(|(s, m)| (s - m).abs() < 1e-9)
+        );
+    }
+}
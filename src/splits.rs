@@ -0,0 +1,256 @@
+// Mile or kilometer splits: the time, pace, elevation change and heart
+// rate of each mile (or kilometer) in turn, plus whatever's left over at
+// the end.
+//
+// Distance comes from the trkpts' recorded distances when they have them
+// and from their speeds otherwise.  A split's boundaries fall between
+// trkpts, so their times and elevations are interpolated.
+
+use {
+    crate::gpx::{Gpx, METERS_PER_MILE, Trkpt, seconds_between},
+    chrono::{DateTime, TimeDelta, Utc},
+    digital_duration_nom::duration::Duration,
+    std::{
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitUnit {
+    Mile,
+    Kilometer,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Split {
+    pub start: DateTime<Utc>,
+    pub stop: DateTime<Utc>,
+    // The unit's length, except for what's left at the end
+    pub meters: f64,
+    // None when there are no elevations
+    pub elevation_change: Option<f64>,
+    // The average of the readings; None when there are none
+    pub heart_rate: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct Splits {
+    pub unit: SplitUnit,
+    pub splits: Vec<Split>,
+}
+
+impl SplitUnit {
+    pub fn meters(self) -> f64 {
+        match self {
+            SplitUnit::Mile => METERS_PER_MILE,
+            SplitUnit::Kilometer => 1000.0,
+        }
+    }
+}
+
+impl FromStr for SplitUnit {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "mile" | "mi" => Ok(SplitUnit::Mile),
+            "kilometer" | "km" => Ok(SplitUnit::Kilometer),
+            _ => Err(format!("unknown split \"{string}\" (expected mile or km)")),
+        }
+    }
+}
+
+impl Display for SplitUnit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SplitUnit::Mile => "mi",
+            SplitUnit::Kilometer => "km",
+        })
+    }
+}
+
+impl Split {
+    pub fn seconds(&self) -> f64 {
+        seconds_between(self.start, self.stop)
+    }
+
+    // Per unit, even for a partial split
+    pub fn pace(&self, unit: SplitUnit) -> Duration {
+        Duration::from(self.seconds() / self.meters * unit.meters())
+    }
+}
+
+// Where the activity was, how far along
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    time: DateTime<Utc>,
+    meters: f64,
+    elevation: Option<f64>,
+}
+
+fn samples(trkpts: &[Trkpt]) -> Vec<Sample> {
+    let recorded = trkpts.iter().any(|t| t.meters.is_some());
+    let mut samples = Vec::<Sample>::with_capacity(trkpts.len());
+
+    for trkpt in trkpts {
+        let meters = if recorded {
+            trkpt.meters
+        } else {
+            let last = samples.last();
+            let seconds = last.map_or(0.0, |l| seconds_between(l.time, trkpt.time));
+            Some(last.map_or(0.0, |l| l.meters) + trkpt.meters_per_second.unwrap_or(0.0) * seconds)
+        };
+        if let Some(meters) = meters {
+            samples.push(Sample {
+                time: trkpt.time,
+                meters,
+                elevation: trkpt.elevation_meters,
+            });
+        }
+    }
+    samples
+}
+
+// The sample at meters along, interpolated between samples[i - 1] and
+// samples[i].
+fn interpolate(samples: &[Sample], i: usize, meters: f64) -> Sample {
+    let (before, after) = (&samples[i - 1], &samples[i]);
+    let fraction = (meters - before.meters) / (after.meters - before.meters);
+    let seconds = seconds_between(before.time, after.time);
+    Sample {
+        time: before.time + TimeDelta::nanoseconds((seconds * fraction * 1e9).round() as i64),
+        meters,
+        elevation: before
+            .elevation
+            .zip(after.elevation)
+            .map(|(b, a)| b + (a - b) * fraction),
+    }
+}
+
+fn average_heart_rate(trkpts: &[Trkpt], start: DateTime<Utc>, stop: DateTime<Utc>) -> Option<f64> {
+    let readings = trkpts
+        .iter()
+        .filter(|t| t.time >= start && t.time <= stop)
+        .filter_map(|t| t.heart_rate.map(f64::from))
+        .collect::<Vec<_>>();
+    (!readings.is_empty()).then(|| readings.iter().sum::<f64>() / readings.len() as f64)
+}
+
+// How far the activity went, measured the way splits are
+pub(crate) fn total_meters(gpx: &Gpx) -> f64 {
+    let samples = samples(gpx.trkpts());
+    match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) => last.meters - first.meters,
+        _ => 0.0,
+    }
+}
+
+pub fn splits(gpx: &Gpx, unit: SplitUnit) -> Splits {
+    let trkpts = gpx.trkpts();
+    let samples = samples(trkpts);
+    let mut splits = Vec::new();
+    let Some(&first) = samples.first() else {
+        return Splits { unit, splits };
+    };
+    let mut start = first;
+    let split = |start: &Sample, stop: &Sample| Split {
+        start: start.time,
+        stop: stop.time,
+        meters: stop.meters - start.meters,
+        elevation_change: stop.elevation.zip(start.elevation).map(|(s, b)| s - b),
+        heart_rate: average_heart_rate(trkpts, start.time, stop.time),
+    };
+
+    for i in 1..samples.len() {
+        // A sample can end more than one split when the distance jumps.
+        while samples[i].meters >= start.meters + unit.meters() {
+            let stop = interpolate(&samples, i, start.meters + unit.meters());
+            splits.push(split(&start, &stop));
+            start = stop;
+        }
+    }
+    let last = samples[samples.len() - 1];
+    if last.meters > start.meters {
+        splits.push(split(&start, &last));
+    }
+    Splits { unit, splits }
+}
+
+impl Display for Splits {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>5} {:>9} {:>7} {:>6} {:>5}",
+            self.unit, "Time", "Pace", "Elev", "HR"
+        )?;
+        let mut covered = 0.0;
+        for split in &self.splits {
+            covered += split.meters;
+            let elevation = split
+                .elevation_change
+                .map_or_else(|| "-".to_string(), |e| format!("{e:+.0}"));
+            let heart_rate = split
+                .heart_rate
+                .map_or_else(|| "-".to_string(), |hr| format!("{hr:.0}"));
+            writeln!(
+                f,
+                "{:5.2} {:9.1} {:7.1} {elevation:>6} {heart_rate:>5}",
+                covered / self.unit.meters(),
+                Duration::from(split.seconds()),
+                split.pace(self.unit),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splits_from_speed() {
+        // 2.5 km at 4 m/s, climbing 1 m every 100 m
+        let trkpts = (0..=625)
+            .map(|second| {
+                Trkpt::at(second)
+                    .with_speed(4.0)
+                    .with_hr(150)
+                    .with_elevation(1600.0 + 0.04 * second as f64)
+            })
+            .collect::<Vec<_>>();
+        let splits = splits(&Gpx::from(trkpts), SplitUnit::Kilometer).splits;
+
+        assert_eq!(3, splits.len());
+        assert_eq!(250.0, splits[0].seconds());
+        assert_eq!(250.0, splits[1].seconds());
+        assert_eq!(500.0, splits[2].meters);
+        assert_eq!(
+            Duration::from(250.0).as_secs(),
+            splits[2].pace(SplitUnit::Kilometer).as_secs()
+        );
+        assert!((splits[0].elevation_change.unwrap() - 10.0).abs() < 1e-9);
+        assert_eq!(Some(150.0), splits[1].heart_rate);
+    }
+
+    #[test]
+    fn test_splits_from_distance() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let path = "assets/Move_2018_12_17_06_59_29_Running.kml";
+        let gpx = crate::misc::activity_from(path.as_ref()).unwrap();
+        let splits = splits(&gpx, SplitUnit::Mile).splits;
+
+        // A half marathon
+        assert_eq!(14, splits.len());
+        assert!(
+            splits[..13]
+                .iter()
+                .all(|s| (s.meters - METERS_PER_MILE).abs() < 1e-6)
+        );
+        let meters = splits.iter().map(|s| s.meters).sum::<f64>();
+        assert!((meters - 21_097.5).abs() < 50.0, "{meters}");
+        // The splits cover the activity without gaps.
+        assert!(splits.windows(2).all(|w| w[0].stop == w[1].start));
+    }
+}
@@ -0,0 +1,381 @@
+// Training load from heart rate: Banister's TRIMP and hrTSS for each
+// activity, and the acute and chronic training loads (ATL and CTL, a.k.a.
+// fatigue and fitness) and training stress balance (TSB, a.k.a. form) day
+// by day.
+//
+// TRIMP weights each minute by the heart rate reserve it was run at,
+// exponentially, so that hard minutes count for more than easy ones.  hrTSS
+// scales TRIMP so that an hour at lactate threshold heart rate is 100.  ATL
+// and CTL are exponentially weighted averages of the daily hrTSS over 7 and
+// 42 days, and TSB is yesterday's CTL minus yesterday's ATL.
+
+use {
+    crate::{
+        gpx::{Gpx, seconds_between},
+        records::DUPLICATE_SECONDS,
+    },
+    chrono::{DateTime, NaiveDate, Utc},
+    std::{
+        fmt::{self, Display, Formatter},
+        path::PathBuf,
+        str::FromStr,
+    },
+};
+
+const SECONDS_PER_MINUTE: f64 = 60.0;
+// Longer gaps between samples are dropouts, not effort.
+pub(crate) const MAX_GAP_SECONDS: f64 = 60.0;
+const ATL_DAYS: f64 = 7.0;
+const CTL_DAYS: f64 = 42.0;
+
+// Banister's weighting differs by sex.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sex {
+    Male,
+    Female,
+}
+
+#[derive(Clone, Debug)]
+pub struct HeartRateLimits {
+    pub resting: f64,
+    pub max: f64,
+    // Lactate threshold heart rate; estimated from the heart rate reserve
+    // when None
+    pub threshold: Option<f64>,
+    pub sex: Sex,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Load {
+    pub start: DateTime<Utc>,
+    // With heart rates
+    pub minutes: f64,
+    pub trimp: f64,
+    pub hr_tss: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Day {
+    pub date: NaiveDate,
+    // The total of the day's activities
+    pub hr_tss: f64,
+    pub atl: f64,
+    pub ctl: f64,
+    pub tsb: f64,
+}
+
+#[derive(Debug)]
+pub struct TrainingLoad {
+    // Oldest first, without duplicates
+    pub activities: Vec<(PathBuf, Load)>,
+    pub days: Vec<Day>,
+}
+
+impl FromStr for Sex {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "male" | "m" => Ok(Sex::Male),
+            "female" | "f" => Ok(Sex::Female),
+            _ => Err(format!(
+                "unknown sex \"{string}\" (expected male or female)"
+            )),
+        }
+    }
+}
+
+impl Sex {
+    // Banister's a and b in a·e^(b·reserve)
+    fn weighting(self) -> (f64, f64) {
+        match self {
+            Sex::Male => (0.64, 1.92),
+            Sex::Female => (0.86, 1.67),
+        }
+    }
+}
+
+impl HeartRateLimits {
+    // Err unless resting < threshold <= max, since otherwise the reserve
+    // is NaN or the hrTSS infinite
+    pub fn validate(&self) -> Result<(), String> {
+        let (resting, threshold, max) = (self.resting, self.threshold(), self.max);
+        if resting.is_finite() && max.is_finite() && resting < threshold && threshold <= max {
+            Ok(())
+        } else {
+            Err(format!(
+                "heart rates need resting < threshold <= max, but are {resting}, {threshold} and {max}"
+            ))
+        }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+            .unwrap_or(self.resting + 0.85 * (self.max - self.resting))
+    }
+
+    // The fraction of the heart rate reserve that heart_rate uses
+    fn reserve(&self, heart_rate: f64) -> f64 {
+        ((heart_rate - self.resting) / (self.max - self.resting)).clamp(0.0, 1.0)
+    }
+
+    // TRIMP per minute at heart_rate
+    fn trimp_per_minute(&self, heart_rate: f64) -> f64 {
+        let (a, b) = self.sex.weighting();
+        let reserve = self.reserve(heart_rate);
+        reserve * a * (b * reserve).exp()
+    }
+}
+
+// None when gpx has no heart rates.
+pub fn load(gpx: &Gpx, limits: &HeartRateLimits) -> Option<Load> {
+    let trkpts = gpx.trkpts();
+    let mut minutes = 0.0;
+    let mut trimp = 0.0;
+
+    // Each heart rate covers the time since the previous trkpt.
+    for pair in trkpts.windows(2) {
+        let Some(heart_rate) = pair[1].heart_rate else {
+            continue;
+        };
+        let seconds = seconds_between(pair[0].time, pair[1].time);
+        if seconds <= MAX_GAP_SECONDS {
+            minutes += seconds / SECONDS_PER_MINUTE;
+            trimp += seconds / SECONDS_PER_MINUTE * limits.trimp_per_minute(heart_rate.into());
+        }
+    }
+    if minutes == 0.0 {
+        return None;
+    }
+    let hour_at_threshold = 60.0 * limits.trimp_per_minute(limits.threshold());
+    Some(Load {
+        start: trkpts[0].time,
+        minutes,
+        trimp,
+        hr_tss: 100.0 * trimp / hour_at_threshold,
+    })
+}
+
+// Every day from the first activity's through the last one's (in the local
+// time zone), including the days off.
+pub fn daily(loads: &[Load]) -> Vec<Day> {
+    let dates = || loads.iter().map(date_of);
+    let (Some(first), Some(last)) = (dates().min(), dates().max()) else {
+        return Vec::new();
+    };
+    let mut days = Vec::new();
+    let (mut atl, mut ctl) = (0.0, 0.0);
+
+    for date in first.iter_days().take_while(|d| *d <= last) {
+        let hr_tss = loads
+            .iter()
+            .filter(|l| date_of(l) == date)
+            .map(|l| l.hr_tss)
+            .sum::<f64>();
+        let tsb = ctl - atl;
+        atl += (hr_tss - atl) / ATL_DAYS;
+        ctl += (hr_tss - ctl) / CTL_DAYS;
+        days.push(Day {
+            date,
+            hr_tss,
+            atl,
+            ctl,
+            tsb,
+        });
+    }
+    days
+}
+
+fn date_of(load: &Load) -> NaiveDate {
+    load.start.with_timezone(crate::tz()).date_naive()
+}
+
+impl TrainingLoad {
+    // Activities that start within a minute of an earlier one are other
+    // exports of it and don't count again.
+    pub fn new(mut activities: Vec<(PathBuf, Load)>) -> Self {
+        activities.sort_by_key(|(_, load)| load.start);
+        let mut kept = Vec::<(PathBuf, Load)>::with_capacity(activities.len());
+        for (path, load) in activities {
+            if kept.last().is_none_or(|(_, last)| {
+                seconds_between(last.start, load.start) >= DUPLICATE_SECONDS
+            }) {
+                kept.push((path, load));
+            }
+        }
+        let loads = kept
+            .iter()
+            .map(|(_, load)| load.clone())
+            .collect::<Vec<_>>();
+        TrainingLoad {
+            days: daily(&loads),
+            activities: kept,
+        }
+    }
+}
+
+impl Display for Load {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TRIMP: {:.0}, hrTSS: {:.0} ({:.0} minutes with heart rates)",
+            self.trimp, self.hr_tss, self.minutes
+        )
+    }
+}
+
+impl Display for TrainingLoad {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>19} {:>7} {:>6} {:>6}  File",
+            "Start", "Minutes", "TRIMP", "hrTSS"
+        )?;
+        for (path, load) in &self.activities {
+            writeln!(
+                f,
+                "{:>19} {:7.0} {:6.0} {:6.0}  {}",
+                load.start.with_timezone(crate::tz()).naive_local(),
+                load.minutes,
+                load.trimp,
+                load.hr_tss,
+                path.display()
+            )?;
+        }
+        writeln!(
+            f,
+            "{:>10} {:>6} {:>6} {:>6} {:>6}",
+            "Date", "hrTSS", "ATL", "CTL", "TSB"
+        )?;
+        for day in &self.days {
+            writeln!(
+                f,
+                "{:>10} {:6.0} {:6.1} {:6.1} {:+6.1}",
+                day.date, day.hr_tss, day.atl, day.ctl, day.tsb
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::gpx::Trkpt};
+
+    fn limits() -> HeartRateLimits {
+        HeartRateLimits {
+            resting: 50.0,
+            max: 190.0,
+            threshold: Some(169.0),
+            sex: Sex::Male,
+        }
+    }
+
+    // seconds at heart_rate, starting days after December 1, 2018 (at
+    // midnight in Denver)
+    fn run(days: i64, heart_rate: Option<u8>, seconds: i64) -> Gpx {
+        let start = 1_543_647_600 + days * 24 * 60 * 60;
+        Gpx::from(
+            (0..=seconds)
+                .map(|second| Trkpt {
+                    heart_rate,
+                    ..Trkpt::at_timestamp(start + second).with_speed(3.0)
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_load() {
+        let limits = limits();
+        let threshold = load(&run(0, Some(169), 3600), &limits).unwrap();
+        assert!((threshold.hr_tss - 100.0).abs() < 1e-9);
+        assert!((threshold.minutes - 60.0).abs() < 1e-9);
+
+        // Half the reserve for 30 minutes: 30 × 0.5 × 0.64 × e^0.96
+        let easy = load(&run(0, Some(120), 1800), &limits).unwrap();
+        assert!((easy.trimp - 30.0 * 0.5 * 0.64 * 0.96_f64.exp()).abs() < 1e-9);
+
+        let female = HeartRateLimits {
+            sex: Sex::Female,
+            ..limits.clone()
+        };
+        assert!(load(&run(0, Some(120), 1800), &female).unwrap().trimp > easy.trimp);
+
+        assert_eq!(None, load(&run(0, None, 60), &limits));
+    }
+
+    #[test]
+    fn test_validate() {
+        let limits = limits();
+        assert_eq!(Ok(()), limits.validate());
+        assert_eq!(
+            Ok(()),
+            HeartRateLimits {
+                threshold: None,
+                ..limits.clone()
+            }
+            .validate()
+        );
+        assert_eq!(
+            Ok(()),
+            HeartRateLimits {
+                threshold: Some(190.0),
+                ..limits.clone()
+            }
+            .validate()
+        );
+        for (resting, threshold, max) in [
+            (50.0, Some(169.0), 50.0),
+            (190.0, None, 190.0),
+            (200.0, None, 190.0),
+            (50.0, Some(50.0), 190.0),
+            (50.0, Some(40.0), 190.0),
+            (50.0, Some(191.0), 190.0),
+            (f64::NAN, None, 190.0),
+            (50.0, None, f64::INFINITY),
+        ] {
+            let limits = HeartRateLimits {
+                resting,
+                max,
+                threshold,
+                ..limits.clone()
+            };
+            assert!(limits.validate().is_err(), "{limits:?}");
+        }
+    }
+
+    #[test]
+    fn test_daily() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let limits = limits();
+        let activities = [(0, 3600), (0, 1800), (2, 3600)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(days, seconds))| {
+                let gpx = run(days, Some(169), seconds);
+                // Two minutes apart, so the second isn't a duplicate
+                let mut load = load(&gpx, &limits).unwrap();
+                load.start += chrono::TimeDelta::minutes(2 * i as i64);
+                (PathBuf::from(format!("{i}.fit")), load)
+            })
+            .chain([(
+                PathBuf::from("0.kml"),
+                load(&run(0, Some(169), 3600), &limits).unwrap(),
+            )])
+            .collect();
+        let training = TrainingLoad::new(activities);
+
+        assert_eq!(3, training.activities.len());
+        let days = &training.days;
+        assert_eq!(3, days.len());
+        assert!((days[0].hr_tss - 150.0).abs() < 1e-9);
+        assert!((days[0].atl - 150.0 / 7.0).abs() < 1e-9);
+        assert!((days[0].ctl - 150.0 / 42.0).abs() < 1e-9);
+        assert_eq!(0.0, days[0].tsb);
+        assert_eq!(0.0, days[1].hr_tss);
+        assert!((days[1].tsb - (days[0].ctl - days[0].atl)).abs() < 1e-9);
+        assert!(days[1].atl < days[0].atl);
+        assert!((days[2].hr_tss - 100.0).abs() < 1e-9);
+    }
+}
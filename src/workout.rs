@@ -0,0 +1,366 @@
+// Structured workouts, e.g., "4×(3:00/1:30), 6×(1:00/1:00), 2:00 rest
+// between sets", for sessions (pyramids, ladders, mixed sets) whose reps
+// don't all share one duration and rest.
+//
+// A set is an optional repetition count ("4×", "4x" or "4*") followed by
+// work/rest durations, optionally in parentheses, so a pyramid can be
+// written as "1:00/1:00, 2:00/2:00, 3:00/2:00, 2:00/1:00, 1:00/1:00".  The
+// optional trailing rest replaces the rest after the last rep of each set.
+//
+// Matching slides the whole template over the activity, letting each rep
+// start a little earlier or later than planned relative to the previous
+// one, and keeps the placement where work is fastest relative to the rest
+// that follows it.
+
+use {
+    crate::{
+        detect::{self, PrefixSums},
+        gpx::{Gpx, IntervalReport},
+    },
+    chrono::TimeDelta,
+    digital_duration_nom::duration::{Duration, duration_parser},
+    nom::{
+        IResult, Parser,
+        branch::alt,
+        bytes::complete::tag,
+        character::complete::{digit1, space0, space1},
+        combinator::{all_consuming, map, map_res, opt},
+        multi::separated_list1,
+        sequence::{delimited, preceded, separated_pair, terminated},
+    },
+    std::{
+        error,
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+        time,
+    },
+};
+
+// A rep may start this fraction of the planned start-to-start time early
+// or late relative to the previous rep, but never by less than
+// MIN_DRIFT_SECONDS.
+const DRIFT: f64 = 0.15;
+const MIN_DRIFT_SECONDS: f64 = 10.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Set {
+    pub reps: usize,
+    pub work: time::Duration,
+    pub rest: time::Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workout {
+    pub sets: Vec<Set>,
+    pub set_rest: Option<time::Duration>,
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl error::Error for ParseError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Bad workout \"{}\"", self.0)
+    }
+}
+
+fn std_duration(input: &str) -> IResult<&str, time::Duration> {
+    map(duration_parser, Into::into).parse(input)
+}
+
+fn work_rest(input: &str) -> IResult<&str, (time::Duration, time::Duration)> {
+    separated_pair(std_duration, tag("/"), std_duration).parse(input)
+}
+
+fn set(input: &str) -> IResult<&str, Set> {
+    map(
+        (
+            opt(terminated(
+                map_res(digit1, str::parse::<usize>),
+                alt((tag("×"), tag("x"), tag("X"), tag("*"))),
+            )),
+            alt((delimited(tag("("), work_rest, tag(")")), work_rest)),
+        ),
+        |(reps, (work, rest))| Set {
+            reps: reps.unwrap_or(1),
+            work,
+            rest,
+        },
+    )
+    .parse(input)
+}
+
+fn comma(input: &str) -> IResult<&str, &str> {
+    delimited(space0, tag(","), space0).parse(input)
+}
+
+fn set_rest(input: &str) -> IResult<&str, time::Duration> {
+    preceded(
+        comma,
+        terminated(
+            std_duration,
+            (space1, opt(tag("rest ")), tag("between sets")),
+        ),
+    )
+    .parse(input)
+}
+
+fn workout(input: &str) -> IResult<&str, Workout> {
+    map(
+        all_consuming(delimited(
+            space0,
+            (separated_list1(comma, set), opt(set_rest)),
+            space0,
+        )),
+        |(sets, set_rest)| Workout { sets, set_rest },
+    )
+    .parse(input)
+}
+
+impl FromStr for Workout {
+    type Err = ParseError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match workout(string) {
+            Ok((_, workout)) if workout.sets.iter().all(|s| s.reps > 0) => Ok(workout),
+            _ => Err(ParseError(string.to_string())),
+        }
+    }
+}
+
+impl Display for Workout {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let ddn = |d: &time::Duration| Duration::from(d.as_secs_f64());
+
+        for (i, set) in self.sets.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}×({}/{})", set.reps, ddn(&set.work), ddn(&set.rest))?;
+        }
+        if let Some(rest) = &self.set_rest {
+            write!(f, ", {} rest between sets", ddn(rest))?;
+        }
+        Ok(())
+    }
+}
+
+// A rep's work and the rest that follows it, in whole seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rep {
+    work: usize,
+    rest: usize,
+}
+
+impl Workout {
+    fn reps(&self) -> Vec<Rep> {
+        let seconds = |d: &time::Duration| d.as_secs_f64().round() as usize;
+        let mut reps = Vec::new();
+
+        for (i, set) in self.sets.iter().enumerate() {
+            for rep in 0..set.reps {
+                let last_of_set = rep + 1 == set.reps && i + 1 < self.sets.len();
+                let rest = match &self.set_rest {
+                    Some(set_rest) if last_of_set => set_rest,
+                    _ => &set.rest,
+                };
+                reps.push(Rep {
+                    work: seconds(&set.work),
+                    rest: seconds(rest),
+                });
+            }
+        }
+        reps
+    }
+
+    // Returns the start (in seconds from the start of speeds) of every rep.
+    fn align(reps: &[Rep], speeds: &[f64]) -> Option<Vec<usize>> {
+        let n = speeds.len();
+        let sums = PrefixSums::new(speeds);
+        // How much faster the work is than the rest that follows it.  A
+        // trkpt's speed covers the time since the previous one, so work
+        // that starts at start is in speeds[start + 1..=start + rep.work].
+        let score = |rep: &Rep, start: usize| {
+            let stop = start + rep.work + 1;
+            if rep.work == 0 || stop > n {
+                return f64::NEG_INFINITY;
+            }
+            let work = sums.mean(start + 1, stop);
+            let recovered = (stop + rep.rest).min(n);
+            if recovered > stop {
+                work - sums.mean(stop, recovered)
+            } else {
+                work
+            }
+        };
+
+        let first = reps.first()?;
+        let mut best = vec![(0..n).map(|start| score(first, start)).collect::<Vec<_>>()];
+        let mut previous = Vec::new();
+
+        for (j, rep) in reps.iter().enumerate().skip(1) {
+            let planned = reps[j - 1].work + reps[j - 1].rest;
+            let drift = (planned as f64 * DRIFT).max(MIN_DRIFT_SECONDS).round() as usize;
+            let before = &best[j - 1];
+            let mut scores = vec![f64::NEG_INFINITY; n];
+            let mut froms = vec![0; n];
+
+            for start in 0..n {
+                let earliest = start.saturating_sub(planned + drift);
+                let latest = match start.checked_sub(planned.saturating_sub(drift).max(1)) {
+                    Some(latest) => latest,
+                    None => continue,
+                };
+                let Some((from, total)) = (earliest..=latest)
+                    .map(|from| (from, before[from]))
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                else {
+                    continue;
+                };
+                if total.is_finite() {
+                    scores[start] = total + score(rep, start);
+                    froms[start] = from;
+                }
+            }
+            best.push(scores);
+            previous.push(froms);
+        }
+
+        let (mut start, total) = best
+            .last()?
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        if !total.is_finite() {
+            return None;
+        }
+        let mut starts = vec![start];
+        for froms in previous.iter().rev() {
+            start = froms[start];
+            starts.push(start);
+        }
+        starts.reverse();
+        Some(starts)
+    }
+
+    // The reps of this workout as found in gpx, in order.  None when the
+    // activity is too short to hold the workout.
+    pub fn find(&self, gpx: &Gpx) -> Option<IntervalReport> {
+        let first = gpx.trkpts().first()?.time;
+        let reps = self.reps();
+        let starts = Self::align(&reps, &detect::per_second_speeds(gpx.trkpts()))?;
+        let intervals = reps
+            .iter()
+            .zip(starts)
+            .map(|(rep, start)| {
+                let start = first + TimeDelta::seconds(start as i64);
+                gpx.interval_between(start, start + TimeDelta::seconds(rep.work as i64))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(IntervalReport::new(intervals))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpx::Trkpt;
+
+    fn minutes(minutes: u64, seconds: u64) -> time::Duration {
+        time::Duration::from_secs(minutes * 60 + seconds)
+    }
+
+    #[test]
+    fn test_parse() {
+        let workout = Workout::from_str("4×(3:00/1:30), 6x(1:00/1:00), 2:00 rest between sets");
+        assert_eq!(
+            Workout {
+                sets: vec![
+                    Set {
+                        reps: 4,
+                        work: minutes(3, 0),
+                        rest: minutes(1, 30)
+                    },
+                    Set {
+                        reps: 6,
+                        work: minutes(1, 0),
+                        rest: minutes(1, 0)
+                    },
+                ],
+                set_rest: Some(minutes(2, 0)),
+            },
+            workout.unwrap()
+        );
+
+        let pyramid = Workout::from_str("1:00/1:00, 2:00/1:00, 1:00/1:00").unwrap();
+        assert_eq!(3, pyramid.sets.len());
+        assert!(pyramid.sets.iter().all(|s| s.reps == 1));
+        assert_eq!(None, pyramid.set_rest);
+
+        assert!(Workout::from_str("4×(3:00)").is_err());
+        assert!(Workout::from_str("0×(3:00/1:00)").is_err());
+    }
+
+    #[test]
+    fn test_reps() {
+        let workout = Workout::from_str("2×(1:00/0:30), 1×(2:00/1:00), 3:00 between sets").unwrap();
+        assert_eq!(
+            vec![
+                Rep { work: 60, rest: 30 },
+                Rep {
+                    work: 60,
+                    rest: 180
+                },
+                Rep {
+                    work: 120,
+                    rest: 60
+                },
+            ],
+            workout.reps()
+        );
+    }
+
+    #[test]
+    fn test_find() {
+        // A 60/120/180/120/60 pyramid with 60 s rests, run at 4 m/s (the
+        // first rep started three seconds late) between 2 m/s jogging.
+        let fast = [
+            (604..664),
+            (721..841),
+            (901..1081),
+            (1141..1261),
+            (1321..1381),
+        ];
+        let trkpts = (0..2000)
+            .map(|second| {
+                Trkpt::at(second).with_speed(if fast.iter().any(|r| r.contains(&second)) {
+                    4.0
+                } else {
+                    2.0
+                })
+            })
+            .collect::<Vec<_>>();
+        let gpx = Gpx::from(trkpts);
+        let workout =
+            Workout::from_str("1:00/1:00, 2:00/1:00, 3:00/1:00, 2:00/1:00, 1:00/1:00").unwrap();
+        let report = workout.find(&gpx).unwrap();
+
+        assert_eq!(5, report.intervals.len());
+        assert!(
+            report
+                .intervals
+                .iter()
+                .all(|i| (i.rank() - 4.0).abs() < 1e-9)
+        );
+        let starts = report
+            .intervals
+            .iter()
+            .map(|i| (i.start() - gpx.trkpts()[0].time).num_seconds())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![603, 720, 900, 1140, 1320], starts);
+    }
+}
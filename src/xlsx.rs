@@ -1 +1,519 @@
-pub struct Xlsx {}
+// Reader for the xlsx spreadsheets that Movescount used to export, and a
+// bare-bones writer for our own exports.
+//
+// An xlsx file is a zip archive full of XML.  The workbook names its sheets
+// and the workbook's relationships file says which part holds each sheet.
+// Cell text either lives in the cell itself or is an index into the shared
+// strings part.  The Suunto exports have a single sheet whose first couple
+// of rows summarize the move and its marks, and whose "Move samples"
+// columns hold one sample per row.
+
+use {
+    crate::{
+        export::{Cell, Sheet},
+        gpx::{Gpx, Trkpt},
+    },
+    chrono::{DateTime, NaiveDateTime, TimeZone, Utc},
+    roxmltree::{Document, Node},
+    std::{
+        collections::HashMap,
+        error,
+        fmt::{self, Display, Formatter},
+        io::{self, Read, Seek, Write},
+        str::FromStr,
+    },
+    zip::{CompressionMethod, ZipArchive, ZipWriter, result::ZipError, write::SimpleFileOptions},
+};
+
+const WORKBOOK: &str = "xl/workbook.xml";
+const WORKBOOK_RELS: &str = "xl/_rels/workbook.xml.rels";
+const SHARED_STRINGS: &str = "xl/sharedStrings.xml";
+const SAMPLES_MARKER: &str = "Move samples";
+const MAIN_NS: &str = "http://schemas.openxmlformats.org/spreadsheetml/2006/main";
+const REL_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const PACKAGE_REL_NS: &str = "http://schemas.openxmlformats.org/package/2006/relationships";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Zip(ZipError),
+    Xml(roxmltree::Error),
+    Format(String),
+}
+
+impl error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Zip(e) => write!(f, "{e}"),
+            Error::Xml(e) => write!(f, "{e}"),
+            Error::Format(message) => write!(f, "Not a Suunto xlsx export: {message}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(e: ZipError) -> Self {
+        Error::Zip(e)
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(e: roxmltree::Error) -> Self {
+        Error::Xml(e)
+    }
+}
+
+fn format_error<T>(message: &str) -> Result<T, Error> {
+    Err(Error::Format(message.to_string()))
+}
+
+#[derive(Debug)]
+pub struct Xlsx {
+    trkpts: Vec<Trkpt>,
+}
+
+// The sample columns we know how to map onto a Trkpt.
+#[derive(Default)]
+struct Columns {
+    time: Option<usize>,
+    altitude: Option<usize>,
+    distance: Option<usize>,
+    heart_rate: Option<usize>,
+    speed: Option<usize>,
+    vertical_speed: Option<usize>,
+    cadence: Option<usize>,
+}
+
+impl Xlsx {
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<Self, Error> {
+        let mut archive = ZipArchive::new(reader)?;
+        let sheet_path = Self::first_sheet_path(&mut archive)?;
+        let shared_strings = match Self::part(&mut archive, SHARED_STRINGS) {
+            Ok(xml) => Self::shared_strings(&Document::parse(&xml)?),
+            Err(Error::Zip(ZipError::FileNotFound)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let sheet = Self::part(&mut archive, &sheet_path)?;
+        let rows = Self::rows(&Document::parse(&sheet)?, &shared_strings);
+
+        Ok(Xlsx {
+            trkpts: Self::trkpts_from(&rows)?,
+        })
+    }
+
+    pub fn trkpts(&self) -> &[Trkpt] {
+        &self.trkpts
+    }
+
+    fn part<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<String, Error> {
+        let mut contents = String::new();
+        archive.by_name(name)?.read_to_string(&mut contents)?;
+        // The Movescount exports start every part with a byte order mark.
+        Ok(contents.trim_start_matches('\u{feff}').to_string())
+    }
+
+    fn first_sheet_path<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<String, Error> {
+        let workbook = Self::part(archive, WORKBOOK)?;
+        let workbook = Document::parse(&workbook)?;
+        let Some(id) = workbook
+            .descendants()
+            .find(|n| n.has_tag_name("sheet"))
+            .and_then(|sheet| {
+                sheet
+                    .attributes()
+                    .find(|a| a.name() == "id")
+                    .map(|a| a.value().to_string())
+            })
+        else {
+            return format_error("workbook has no sheets");
+        };
+
+        let rels = Self::part(archive, WORKBOOK_RELS)?;
+        let rels = Document::parse(&rels)?;
+        let Some(target) = rels
+            .descendants()
+            .filter(|n| n.has_tag_name("Relationship"))
+            .find(|n| n.attribute("Id") == Some(&id))
+            .and_then(|n| n.attribute("Target"))
+        else {
+            return format_error("can't find the first sheet");
+        };
+
+        // Targets are either absolute within the archive or relative to xl/.
+        Ok(match target.strip_prefix('/') {
+            Some(absolute) => absolute.to_string(),
+            None => format!("xl/{target}"),
+        })
+    }
+
+    fn shared_strings(doc: &Document) -> Vec<String> {
+        doc.descendants()
+            .filter(|n| n.has_tag_name("si"))
+            .map(|si| Self::text_runs(&si))
+            .collect()
+    }
+
+    fn text_runs(node: &Node) -> String {
+        node.descendants()
+            .filter(|n| n.has_tag_name("t"))
+            .filter_map(|t| t.text())
+            .collect()
+    }
+
+    // Zero-based column index from a cell reference like "HS3".
+    fn column(reference: &str) -> Option<usize> {
+        let letters = reference
+            .bytes()
+            .take_while(u8::is_ascii_uppercase)
+            .collect::<Vec<_>>();
+        if letters.is_empty() {
+            return None;
+        }
+        Some(
+            letters.iter().fold(0, |column, letter| {
+                column * 26 + usize::from(letter - b'A' + 1)
+            }) - 1,
+        )
+    }
+
+    fn cell_value(cell: &Node, shared_strings: &[String]) -> Option<String> {
+        let value = || {
+            cell.children()
+                .find(|n| n.has_tag_name("v"))
+                .and_then(|v| v.text())
+        };
+
+        match cell.attribute("t") {
+            Some("s") => value()
+                .and_then(|index| usize::from_str(index).ok())
+                .and_then(|index| shared_strings.get(index).cloned()),
+            Some("inlineStr") => cell
+                .children()
+                .find(|n| n.has_tag_name("is"))
+                .map(|is| Self::text_runs(&is)),
+            _ => value().map(str::to_string),
+        }
+    }
+
+    fn rows(doc: &Document, shared_strings: &[String]) -> Vec<HashMap<usize, String>> {
+        doc.descendants()
+            .filter(|n| n.has_tag_name("row"))
+            .map(|row| {
+                row.children()
+                    .filter(|n| n.has_tag_name("c"))
+                    .filter_map(|cell| {
+                        let column = Self::column(cell.attribute("r")?)?;
+                        let value = Self::cell_value(&cell, shared_strings)?;
+                        Some((column, value))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn columns(rows: &[HashMap<usize, String>]) -> Result<(usize, Columns), Error> {
+        let Some((marker_row, first_column)) = rows.iter().enumerate().find_map(|(i, row)| {
+            row.iter()
+                .find(|(_, value)| *value == SAMPLES_MARKER)
+                .map(|(column, _)| (i, *column))
+        }) else {
+            return format_error("no move samples");
+        };
+        let header_row = marker_row + 1;
+        let Some(headers) = rows.get(header_row) else {
+            return format_error("no move sample headers");
+        };
+
+        let mut columns = Columns::default();
+        for (column, header) in headers.iter().filter(|(c, _)| **c >= first_column) {
+            let slot = match header.as_str() {
+                "LocalTime" => &mut columns.time,
+                "Altitude" => &mut columns.altitude,
+                "Distance" => &mut columns.distance,
+                "HeartRate" => &mut columns.heart_rate,
+                "Speed" => &mut columns.speed,
+                "VerticalSpeed" => &mut columns.vertical_speed,
+                "Cadence" => &mut columns.cadence,
+                _ => continue,
+            };
+            slot.get_or_insert(*column);
+        }
+        if columns.time.is_none() {
+            return format_error("move samples have no LocalTime column");
+        }
+
+        Ok((header_row + 1, columns))
+    }
+
+    // The exports record local time without an offset.
+    fn time_from_local(local: &str) -> Option<DateTime<Utc>> {
+        let naive = NaiveDateTime::from_str(local).ok()?;
+        crate::tz()
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|time| time.with_timezone(&Utc))
+    }
+
+    fn trkpts_from(rows: &[HashMap<usize, String>]) -> Result<Vec<Trkpt>, Error> {
+        let (first_sample_row, columns) = Self::columns(rows)?;
+        let f64_at = |row: &HashMap<usize, String>, column: Option<usize>| {
+            column
+                .and_then(|c| row.get(&c))
+                .and_then(|value| f64::from_str(value).ok())
+        };
+        let u8_at = |row: &HashMap<usize, String>, column: Option<usize>| {
+            f64_at(row, column).map(|value| value.round().clamp(0.0, 255.0) as u8)
+        };
+
+        let mut trkpts = Vec::with_capacity(rows.len() - first_sample_row);
+        for row in &rows[first_sample_row..] {
+            // Trailing rows only carry beat-to-beat intervals.
+            let Some(local) = columns.time.and_then(|c| row.get(&c)) else {
+                continue;
+            };
+            let Some(time) = Self::time_from_local(local) else {
+                return Err(Error::Format(format!("bad sample time {local}")));
+            };
+            trkpts.push(Trkpt {
+                time,
+                meters_per_second: f64_at(row, columns.speed),
+                meters: f64_at(row, columns.distance),
+                heart_rate: u8_at(row, columns.heart_rate),
+                cadence: u8_at(row, columns.cadence),
+                elevation_meters: f64_at(row, columns.altitude),
+                vertical_mps: f64_at(row, columns.vertical_speed),
+                lat: None,
+                lon: None,
+            });
+        }
+        // Movescount writes an altitude of 0 until the barometer has a
+        // reading, which would otherwise look like a 1,600 m climb.
+        for trkpt in trkpts
+            .iter_mut()
+            .take_while(|t| t.elevation_meters == Some(0.0))
+        {
+            trkpt.elevation_meters = None;
+        }
+
+        Ok(trkpts)
+    }
+}
+
+impl From<Xlsx> for Gpx {
+    fn from(xlsx: Xlsx) -> Self {
+        Gpx::from(xlsx.trkpts)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn column_name(mut column: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (column % 26) as u8);
+        if column < 26 {
+            break;
+        }
+        column = column / 26 - 1;
+    }
+    name.iter().rev().map(|&b| char::from(b)).collect()
+}
+
+// Excel refuses sheet names longer than 31 characters, names containing
+// any of []:*?/\ and duplicate names.
+fn sheet_names(sheets: &[Sheet]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::with_capacity(sheets.len());
+    for (i, sheet) in sheets.iter().enumerate() {
+        let cleaned = sheet
+            .name
+            .chars()
+            .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+            .take(31)
+            .collect::<String>();
+        let name = if cleaned.is_empty() || names.contains(&cleaned) {
+            let suffix = format!(" ({})", i + 1);
+            let keep = 31 - suffix.len();
+            format!("{}{suffix}", cleaned.chars().take(keep).collect::<String>())
+        } else {
+            cleaned
+        };
+        names.push(name);
+    }
+    names
+}
+
+fn worksheet_xml(sheet: &Sheet) -> String {
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><worksheet xmlns="{MAIN_NS}"><sheetData>"#
+    );
+    let header = sheet
+        .header
+        .iter()
+        .map(|h| Cell::Text(h.to_string()))
+        .collect::<Vec<_>>();
+
+    for (r, row) in std::iter::once(&header).chain(&sheet.rows).enumerate() {
+        let r = r + 1;
+        xml.push_str(&format!(r#"<row r="{r}">"#));
+        for (c, cell) in row.iter().enumerate() {
+            let reference = format!("{}{r}", column_name(c));
+            match cell {
+                Cell::Number(number) if number.is_finite() => {
+                    xml.push_str(&format!(r#"<c r="{reference}"><v>{number}</v></c>"#))
+                }
+                Cell::Text(text) => xml.push_str(&format!(
+                    r#"<c r="{reference}" t="inlineStr"><is><t>{}</t></is></c>"#,
+                    escape(text)
+                )),
+                _ => (),
+            }
+        }
+        xml.push_str("</row>");
+    }
+    xml.push_str("</sheetData></worksheet>");
+    xml
+}
+
+pub fn write_workbook<W: Write + Seek>(sheets: &[Sheet], writer: W) -> Result<(), Error> {
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let names = sheet_names(sheets);
+
+    let overrides = (1..=sheets.len())
+        .map(|i| {
+            format!(
+                r#"<Override PartName="/xl/worksheets/sheet{i}.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/>"#
+            )
+        })
+        .collect::<String>();
+    let content_types = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/>{overrides}</Types>"#
+    );
+    let rels = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Relationships xmlns="{PACKAGE_REL_NS}"><Relationship Id="rId1" Type="{REL_NS}/officeDocument" Target="xl/workbook.xml"/></Relationships>"#
+    );
+    let sheet_elements = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let i = i + 1;
+            format!(
+                r#"<sheet name="{}" sheetId="{i}" r:id="rId{i}"/>"#,
+                escape(name)
+            )
+        })
+        .collect::<String>();
+    let workbook = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><workbook xmlns="{MAIN_NS}" xmlns:r="{REL_NS}"><sheets>{sheet_elements}</sheets></workbook>"#
+    );
+    let relationships = (1..=sheets.len())
+        .map(|i| {
+            format!(
+                r#"<Relationship Id="rId{i}" Type="{REL_NS}/worksheet" Target="worksheets/sheet{i}.xml"/>"#
+            )
+        })
+        .collect::<String>();
+    let workbook_rels = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><Relationships xmlns="{PACKAGE_REL_NS}">{relationships}</Relationships>"#
+    );
+
+    let mut parts = vec![
+        ("[Content_Types].xml".to_string(), content_types),
+        ("_rels/.rels".to_string(), rels),
+        (WORKBOOK.to_string(), workbook),
+        (WORKBOOK_RELS.to_string(), workbook_rels),
+    ];
+    for (i, sheet) in sheets.iter().enumerate() {
+        parts.push((
+            format!("xl/worksheets/sheet{}.xml", i + 1),
+            worksheet_xml(sheet),
+        ));
+    }
+    for (name, xml) in parts {
+        zip.start_file(name, options)?;
+        zip.write_all(xml.as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_column() {
+        assert_eq!(Some(0), Xlsx::column("A1"));
+        assert_eq!(Some(25), Xlsx::column("Z2"));
+        assert_eq!(Some(26), Xlsx::column("AA2"));
+        assert_eq!(Some(226), Xlsx::column("HS3"));
+        assert_eq!(None, Xlsx::column("3"));
+    }
+
+    #[test]
+    fn test_column_name() {
+        for column in [0, 25, 26, 226, 701, 702] {
+            assert_eq!(
+                Some(column),
+                Xlsx::column(&format!("{}1", column_name(column)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_workbook() {
+        let mut sheet = Sheet::new("12/17 <run>", vec!["Pace", "Start"]);
+        sheet.push(vec![Cell::Number(439.8), Cell::Text("06:59".to_string())]);
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        write_workbook(&[sheet.clone(), sheet], &mut buffer).unwrap();
+
+        let mut archive = ZipArchive::new(buffer).unwrap();
+        assert_eq!(
+            "xl/worksheets/sheet1.xml",
+            Xlsx::first_sheet_path(&mut archive).unwrap()
+        );
+        let xml = Xlsx::part(&mut archive, "xl/worksheets/sheet1.xml").unwrap();
+        let rows = Xlsx::rows(&Document::parse(&xml).unwrap(), &[]);
+        assert_eq!("Pace", rows[0][&0]);
+        assert_eq!("439.8", rows[1][&0]);
+        assert_eq!("06:59", rows[1][&1]);
+        let workbook = Xlsx::part(&mut archive, WORKBOOK).unwrap();
+        assert!(workbook.contains(r#"name="12_17 &lt;run&gt;""#));
+        assert!(workbook.contains(r#"name="12_17 &lt;run&gt; (2)""#));
+    }
+
+    #[test]
+    fn test_move_samples() {
+        crate::set_tz(Some(chrono_tz::Tz::America__Denver));
+        let file = File::open("assets/Move_2018_12_17_06_59_29_Running.xlsx").unwrap();
+        let xlsx = Xlsx::from_reader(file).unwrap();
+        let trkpts = xlsx.trkpts();
+        let first = &trkpts[0];
+
+        assert_eq!(
+            DateTime::<Utc>::from_str("2018-12-17T13:59:29Z").unwrap(),
+            first.time
+        );
+        assert_eq!(Some(88), first.heart_rate);
+        assert_eq!(Some(1.2), first.meters_per_second);
+        assert_eq!(None, first.elevation_meters);
+        assert_eq!(Some(1688.0), trkpts[2].elevation_meters);
+        assert!(trkpts.iter().all(|t| t.meters_per_second.is_some()));
+        assert!(trkpts.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+}
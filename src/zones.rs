@@ -0,0 +1,414 @@
+// Heart rate and pace zones from an athlete profile, and the time spent in
+// each zone over an activity and over each of its intervals.
+//
+// A profile is a text file of "name = value" lines; "#" starts a comment:
+//
+//     resting_hr = 48
+//     max_hr = 188
+//     threshold_hr = 169
+//     threshold_pace = 7:10      # per mile
+//     sex = female
+//     hr_zones = 140 152 160 169
+//     pace_zones = 9:15 8:10 7:35 7:05
+//
+// Everything is optional.  hr_zones are the heart rates at which zones 2
+// and up start and pace_zones the (per mile) paces, slowest first.  Without
+// them, the zones are Friel's, from the threshold heart rate (estimated
+// from resting_hr and max_hr if need be) and the threshold pace.
+//
+// Each sample's heart rate or speed covers the time since the previous
+// sample, as in training_load.
+
+use {
+    crate::{
+        gpx::{Gpx, IntervalReport, METERS_PER_MILE, Trkpt, seconds_between},
+        training_load::{HeartRateLimits, MAX_GAP_SECONDS, Sex},
+    },
+    chrono::{DateTime, Utc},
+    digital_duration_nom::duration::{Duration, duration_parser},
+    nom::{Parser, combinator::all_consuming},
+    std::{
+        fmt::{self, Display, Formatter},
+        fs, io,
+        path::Path,
+        str::FromStr,
+        time,
+    },
+};
+
+// Where zones 2 through 5 start, as fractions of the threshold heart rate
+const HR_ZONE_FRACTIONS: [f64; 4] = [0.85, 0.90, 0.95, 1.00];
+// Where zones 2 through 5 start, as multiples of the threshold pace
+const PACE_ZONE_MULTIPLES: [f64; 4] = [1.29, 1.14, 1.06, 0.99];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub resting_hr: Option<f64>,
+    pub max_hr: Option<f64>,
+    pub threshold_hr: Option<f64>,
+    // Seconds per mile
+    pub threshold_pace: Option<f64>,
+    pub sex: Option<Sex>,
+    pub hr_zones: Option<Zones>,
+    pub pace_zones: Option<Zones>,
+}
+
+// Where each zone after the first starts, in ascending order (heart rates,
+// or speeds in meters per second)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zones {
+    starts: Vec<f64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    HeartRate,
+    Pace,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeInZones {
+    // Per zone, starting with zone 1
+    pub seconds: Vec<f64>,
+}
+
+#[derive(Debug)]
+pub struct ZoneReport {
+    origin: Option<DateTime<Utc>>,
+    pub metric: Metric,
+    pub zones: Zones,
+    pub activity: TimeInZones,
+    // Each interval's start and its time in zones
+    pub intervals: Vec<(DateTime<Utc>, TimeInZones)>,
+}
+
+impl Zones {
+    // None unless starts ascend.
+    pub fn new(starts: Vec<f64>) -> Option<Self> {
+        starts
+            .windows(2)
+            .all(|w| w[0] < w[1])
+            .then_some(Zones { starts })
+    }
+
+    // How many zones there are
+    pub fn count(&self) -> usize {
+        self.starts.len() + 1
+    }
+
+    // Zero-based
+    pub fn zone(&self, value: f64) -> usize {
+        self.starts.partition_point(|&start| start <= value)
+    }
+
+    pub fn starts(&self) -> &[f64] {
+        &self.starts
+    }
+}
+
+impl Metric {
+    fn value(self, trkpt: &Trkpt) -> Option<f64> {
+        match self {
+            Metric::HeartRate => trkpt.heart_rate.map(f64::from),
+            Metric::Pace => trkpt.meters_per_second,
+        }
+    }
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.pad(match self {
+            Metric::HeartRate => "Heart rate",
+            Metric::Pace => "Pace",
+        })
+    }
+}
+
+fn pace_seconds(string: &str) -> Result<f64, String> {
+    all_consuming(duration_parser)
+        .parse(string)
+        .map(|(_, duration)| time::Duration::from(duration).as_secs_f64())
+        .map_err(|_| format!("bad pace \"{string}\""))
+}
+
+fn speed_from_pace(seconds_per_mile: f64) -> f64 {
+    METERS_PER_MILE / seconds_per_mile
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e: String| io::Error::other(format!("{}: {e}", path.display())))
+    }
+
+    // Err without both resting and max heart rates, or unless resting <
+    // threshold <= max
+    pub fn heart_rate_limits(&self) -> Result<HeartRateLimits, String> {
+        let (Some(resting), Some(max)) = (self.resting_hr, self.max_hr) else {
+            return Err("need resting and max heart rates".to_string());
+        };
+        let limits = HeartRateLimits {
+            resting,
+            max,
+            threshold: self.threshold_hr,
+            sex: self.sex.unwrap_or(Sex::Male),
+        };
+        limits.validate()?;
+        Ok(limits)
+    }
+
+    pub fn heart_rate_zones(&self) -> Option<Zones> {
+        if let Some(zones) = &self.hr_zones {
+            return Some(zones.clone());
+        }
+        let threshold = self
+            .threshold_hr
+            .or_else(|| self.heart_rate_limits().ok().map(|l| l.threshold()))?;
+        Zones::new(HR_ZONE_FRACTIONS.map(|f| f * threshold).to_vec())
+    }
+
+    pub fn pace_zones(&self) -> Option<Zones> {
+        if let Some(zones) = &self.pace_zones {
+            return Some(zones.clone());
+        }
+        let threshold = self.threshold_pace?;
+        Zones::new(
+            PACE_ZONE_MULTIPLES
+                .map(|m| speed_from_pace(m * threshold))
+                .to_vec(),
+        )
+    }
+
+    pub fn zones(&self, metric: Metric) -> Option<Zones> {
+        match metric {
+            Metric::HeartRate => self.heart_rate_zones(),
+            Metric::Pace => self.pace_zones(),
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let mut profile = Profile::default();
+
+        for (i, line) in string.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |what: &str| format!("line {}: {what}", i + 1);
+            let (name, value) = line
+                .split_once('=')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| error("expected name = value"))?;
+            let number = || {
+                value
+                    .parse::<f64>()
+                    .map_err(|_| error(&format!("bad {name} \"{value}\"")))
+            };
+            let values = || value.split([' ', ',']).filter(|v| !v.is_empty());
+            let unordered = || error(&format!("{name} must go from easiest to hardest"));
+
+            match name {
+                "resting_hr" => profile.resting_hr = Some(number()?),
+                "max_hr" => profile.max_hr = Some(number()?),
+                "threshold_hr" => profile.threshold_hr = Some(number()?),
+                "threshold_pace" => {
+                    profile.threshold_pace = Some(pace_seconds(value).map_err(|e| error(&e))?)
+                }
+                "sex" => profile.sex = Some(value.parse().map_err(|e: String| error(&e))?),
+                "hr_zones" => {
+                    let starts = values()
+                        .map(|v| {
+                            v.parse::<f64>()
+                                .map_err(|_| error(&format!("bad heart rate \"{v}\"")))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    profile.hr_zones = Some(Zones::new(starts).ok_or_else(unordered)?);
+                }
+                "pace_zones" => {
+                    let starts = values()
+                        .map(|v| pace_seconds(v).map(speed_from_pace).map_err(|e| error(&e)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    profile.pace_zones = Some(Zones::new(starts).ok_or_else(unordered)?);
+                }
+                _ => return Err(error(&format!("unknown setting \"{name}\""))),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+pub fn time_in_zones(
+    gpx: &Gpx,
+    metric: Metric,
+    zones: &Zones,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) -> TimeInZones {
+    let trkpts = gpx.trkpts();
+    let mut seconds = vec![0.0; zones.count()];
+    let first = trkpts.partition_point(|t| t.time <= start).max(1);
+
+    for i in first..trkpts.len() {
+        if trkpts[i].time > stop {
+            break;
+        }
+        let Some(value) = metric.value(&trkpts[i]) else {
+            continue;
+        };
+        let covered = seconds_between(trkpts[i - 1].time.max(start), trkpts[i].time);
+        if covered <= MAX_GAP_SECONDS {
+            seconds[zones.zone(value)] += covered;
+        }
+    }
+    TimeInZones { seconds }
+}
+
+impl ZoneReport {
+    // None when the profile doesn't define the zones.
+    pub fn new(
+        gpx: &Gpx,
+        report: &IntervalReport,
+        profile: &Profile,
+        metric: Metric,
+    ) -> Option<Self> {
+        let zones = profile.zones(metric)?;
+        let trkpts = gpx.trkpts();
+        let activity = match (trkpts.first(), trkpts.last()) {
+            (Some(first), Some(last)) => time_in_zones(gpx, metric, &zones, first.time, last.time),
+            _ => TimeInZones {
+                seconds: vec![0.0; zones.count()],
+            },
+        };
+        let intervals = report
+            .intervals
+            .iter()
+            .map(|i| {
+                (
+                    i.start(),
+                    time_in_zones(gpx, metric, &zones, i.start(), i.stop()),
+                )
+            })
+            .collect();
+
+        Some(ZoneReport {
+            origin: trkpts.first().map(|t| t.time),
+            metric,
+            zones,
+            activity,
+            intervals,
+        })
+    }
+}
+
+impl Display for ZoneReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let starts = self
+            .zones
+            .starts()
+            .iter()
+            .map(|&start| match self.metric {
+                Metric::HeartRate => format!("{start:.0}"),
+                Metric::Pace => format!("{:.1}", Duration::from(METERS_PER_MILE / start)),
+            })
+            .collect::<Vec<_>>();
+        writeln!(
+            f,
+            "{} zones 2 and up start at {}",
+            self.metric,
+            starts.join(", ")
+        )?;
+
+        write!(f, "{:>9}", "")?;
+        for zone in 1..=self.zones.count() {
+            write!(f, " {:>9}", format!("Z{zone}"))?;
+        }
+        writeln!(f)?;
+        let mut row = |label: String, time: &TimeInZones| {
+            write!(f, "{label:>9}")?;
+            for &seconds in &time.seconds {
+                write!(f, " {:9.1}", Duration::from(seconds))?;
+            }
+            writeln!(f)
+        };
+        row("Activity".to_string(), &self.activity)?;
+        for (start, time) in &self.intervals {
+            let elapsed = seconds_between(self.origin.unwrap_or(*start), *start);
+            row(format!("{:.1}", Duration::from(elapsed)), time)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let profile = "
+            # An example
+            resting_hr = 48
+            max_hr = 188
+            threshold_pace = 8:00   # per mile
+            sex = female
+            pace_zones = 10:00, 9:00 8:00 7:00
+        "
+        .parse::<Profile>()
+        .unwrap();
+
+        assert_eq!(Some(480.0), profile.threshold_pace);
+        assert_eq!(Some(Sex::Female), profile.sex);
+        // Friel's zones from the estimated threshold, 167
+        let hr = profile.heart_rate_zones().unwrap();
+        assert_eq!(5, hr.count());
+        assert!((hr.starts()[3] - (48.0 + 0.85 * 140.0)).abs() < 1e-9);
+        assert_eq!(Sex::Female, profile.heart_rate_limits().unwrap().sex);
+        assert!(Profile::default().heart_rate_limits().is_err());
+        let backwards = Profile {
+            resting_hr: Some(188.0),
+            max_hr: Some(48.0),
+            ..Profile::default()
+        };
+        assert!(backwards.heart_rate_limits().is_err());
+        let pace = profile.pace_zones().unwrap();
+        assert_eq!(0, pace.zone(speed_from_pace(630.0)));
+        assert_eq!(3, pace.zone(speed_from_pace(480.0)));
+        assert_eq!(4, pace.zone(speed_from_pace(400.0)));
+
+        assert_eq!(
+            Err("line 1: pace_zones must go from easiest to hardest".to_string()),
+            "pace_zones = 7:00 8:00".parse::<Profile>()
+        );
+        assert!("vo2max = 60".parse::<Profile>().is_err());
+        assert_eq!(None, Profile::default().pace_zones());
+    }
+
+    #[test]
+    fn test_time_in_zones() {
+        // 100 s at 120 bpm, then 100 s at 150 and 100 s at 170
+        let trkpts = (0..=300)
+            .map(|second| {
+                Trkpt::at(second).with_speed(3.0).with_hr(match second {
+                    ..=100 => 120,
+                    101..=200 => 150,
+                    _ => 170,
+                })
+            })
+            .collect::<Vec<_>>();
+        let gpx = Gpx::from(trkpts);
+        let profile = "hr_zones = 140 160".parse::<Profile>().unwrap();
+        let start = gpx.trkpts()[150].time;
+        let stop = gpx.trkpts()[250].time;
+        let report = IntervalReport::new(vec![gpx.interval_between(start, stop).unwrap()]);
+
+        let zones = ZoneReport::new(&gpx, &report, &profile, Metric::HeartRate).unwrap();
+        assert_eq!(vec![100.0, 100.0, 100.0], zones.activity.seconds);
+        assert_eq!(vec![0.0, 50.0, 50.0], zones.intervals[0].1.seconds);
+        assert!(ZoneReport::new(&gpx, &report, &profile, Metric::Pace).is_none());
+    }
+}